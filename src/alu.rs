@@ -7,7 +7,7 @@ use crate::opcodes::{
     OP_SHIFT, OP_SUB, OP_XOR, ROL, SHLA, SHR, SHRA,
 };
 
-pub trait ALU {
+pub trait Alu {
     fn process_alu(&mut self, instruction: u8) -> Result<(), Response>;
 }
 
@@ -16,13 +16,13 @@ impl Machine {
         let c = if carry { 0b1000 } else { 0 };
         let v = if overflow { 0b0100 } else { 0 };
         let z = if value == 0 { 0b0010 } else { 0 };
-        let n = if value > 127 { 0b0001 } else { 0 };
+        let n = u8::from(value > 127);
 
         self.set_reg(STATUS, (self.status() & 0b1111_0000) | c | v | z | n)
     }
 }
 
-impl ALU for Machine {
+impl Alu for Machine {
     fn process_alu(&mut self, instruction: u8) -> Result<(), Response> {
         let op = instruction & OPERATION;
         let reg_left = op1!(instruction);
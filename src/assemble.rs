@@ -1,102 +1,330 @@
-use crate::cpu::{
-    ADD, AND, B, CMP, COND_EQ, COND_GT, COND_LT, COND_NE, COND_NONE, EOR, HALT, IMMEDIATE, LDR,
-    LSL, LSR, MOV, MVN, ORR, STR, SUB,
+use crate::expr::{byte, evaluate, unsigned, Scope, Value};
+use crate::image::Image;
+use crate::link::Linker;
+use crate::machine::MEM_SIZE;
+use crate::node::{Node, Register, Type};
+use crate::object::{Object, Placement, Relocation, Segment, Span, Target};
+use crate::opcodes::{
+    ADDSP, ADDSP_SETSP_PUSHALL_POPALL, BEQ_BZ, BGE, BGT, BHI, BHS_BCS, BLE, BLO_BCC, BLS, BLT, BMI,
+    BNE_BNZ, BPL, BR, BVC, BVS, DEC, INC, LDI_INTERRUPT, LDSA, NEG, NOP, NOT, OP_ADD, OP_ADDC,
+    OP_AND, OP_BRANCH, OP_CMP, OP_CRC, OP_HALT, OP_IOI, OP_JSR, OP_LDI_0, OP_LOAD, OP_LOAD_C,
+    OP_MOVE, OP_NOT_NEG_INC_DEC, OP_OR, OP_OSIX, OP_RAND, OP_RTI, OP_RTS, OP_SHIFT, OP_STACK,
+    OP_STORE, OP_SUB, OP_WAIT, OP_XOR, POP, POPALL, PUSH, PUSHALL, ROL, SETSP, SHLA, SHR, SHRA,
 };
-use crate::parse::Comparison;
-use crate::parse::Node;
-use crate::parse::Operand;
 use crate::parse::Parser;
-use crate::storage::Storage;
+use crate::section::Section;
 use crate::stream::Error;
-use crate::stream::Input;
+use crate::symbols::{Location, SymbolTable};
+use crate::token::Range;
+use crate::warning::{check, Warning};
 
-fn encode(n: &Operand) -> u32 {
-    match n {
-        Operand::Literal(n) => IMMEDIATE | n,
-        Operand::Register(n) => *n,
-    }
+pub trait Assemble {
+    /// Build a relocatable object, leaving rsects unplaced
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a label is undefined or defined twice
+    fn compile(&self) -> Result<Object, Error>;
+
+    /// Build a memory image that can be loaded straight into a `Machine`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the program doesn't fit in memory or uses an
+    /// unknown label
+    fn assemble(&self) -> Result<Image, Error>;
 }
 
-pub trait Assemble {
-    fn assemble(&mut self, mem: &mut impl Storage) -> Result<(), Error>;
+/// Collects the bytes of one section along with anything that will need
+/// patching once it's placed
+struct Emitter<'a> {
+    segment: Segment,
+    symbols: &'a SymbolTable,
+    /// Offset of the instruction being encoded
+    start: usize,
+    warnings: Vec<Warning>,
 }
 
-impl Assemble for Input {
-    fn assemble(&mut self, storage: &mut impl Storage) -> Result<(), Error> {
-        let (nodes, labels) = self.parse()?;
-        for (i, node) in nodes.iter().enumerate() {
-            #[allow(clippy::cast_possible_truncation)]
-            let i = i as u32;
-            match node {
-                Node::Ldr(src, mem) => {
-                    let ins = LDR | (src << 16) | mem;
-                    storage.set(i, ins)?;
-                }
-                Node::Str(src, mem) => {
-                    let ins = STR | (src << 16) | mem;
-                    storage.set(i, ins)?;
-                }
-                Node::Add(dest, src, operand) => {
-                    let ins = ADD | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Sub(dest, src, operand) => {
-                    let ins = SUB | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Mov(dest, operand) => {
-                    let ins = MOV | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Cmp(src, operand) => {
-                    let ins = CMP | (src << 16) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::B(cond, label) => {
-                    let cond = match cond {
-                        Comparison::None => COND_NONE,
-                        Comparison::Equal => COND_EQ,
-                        Comparison::NotEqual => COND_NE,
-                        Comparison::Less => COND_LT,
-                        Comparison::Greater => COND_GT,
-                    };
-                    if let Some(pos) = labels.get(label) {
-                        #[allow(clippy::cast_possible_truncation)]
-                        let ins = cond | B | (*pos as u32);
-                        storage.set(i, ins)?;
-                    } else {
-                        return Err(Error::new(format!("Unkown label {}", label), None));
-                    }
-                }
-                Node::And(dest, src, operand) => {
-                    let ins = AND | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Orr(dest, src, operand) => {
-                    let ins = ORR | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Eor(dest, src, operand) => {
-                    let ins = EOR | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Mvn(dest, operand) => {
-                    let ins = MVN | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
-                }
-                Node::Lsl(dest, src, operand) => {
-                    let ins = LSL | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
+impl Scope for Emitter<'_> {
+    fn symbol(&self, name: &str, at: Range) -> Result<Value, Error> {
+        if self.symbols.external(name).is_some() {
+            return Ok(Value::External(name.to_string(), 0));
+        }
+        if let Some(constant) = self.symbols.constant(name) {
+            return Ok(Value::Absolute(constant.value));
+        }
+        Ok(match self.symbols.resolve(name, at)? {
+            Location::Absolute(addr) => Value::Absolute(i32::from(*addr)),
+            Location::Relative(sect, offset) => Value::Relative(sect.clone(), i32::from(*offset)),
+        })
+    }
+
+    fn here(&self, _at: Range) -> Result<Value, Error> {
+        #[allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)]
+        let start = self.start as i32;
+        Ok(match &self.segment.placement {
+            Placement::Absolute(pos) => Value::Absolute(i32::from(*pos) + start),
+            Placement::Relocatable(name) => Value::Relative(name.clone(), start),
+        })
+    }
+}
+
+impl Emitter<'_> {
+    fn push(&mut self, byte: u8) {
+        self.segment.bytes.push(byte);
+    }
+
+    /// Mark the next byte as needing `target` added to it
+    fn relocate(&mut self, target: Target) {
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = self.segment.bytes.len() as u8;
+        self.segment.relocations.push(Relocation { offset, target });
+    }
+
+    /// Emit the byte represented by an operand expression
+    fn value(&mut self, node: &Node) -> Result<(), Error> {
+        self.operand(node, byte)
+    }
+
+    /// Emit an operand that's an address
+    fn address(&mut self, node: &Node) -> Result<(), Error> {
+        self.operand(node, unsigned)
+    }
+
+    /// Emit the byte represented by `node`, fitted into a byte by `fit`
+    fn operand(
+        &mut self,
+        node: &Node,
+        fit: fn(i32, Range, &mut Vec<Warning>) -> u8,
+    ) -> Result<(), Error> {
+        let (target, value) = match evaluate(node, &*self)? {
+            Value::Absolute(value) => (None, value),
+            Value::Relative(sect, offset) => (Some(Target::Section(sect)), offset),
+            Value::External(name, offset) => (Some(Target::External(name)), offset),
+        };
+        if let Some(target) = target {
+            self.relocate(target);
+        }
+        let byte = fit(value, node.range(), &mut self.warnings);
+        self.push(byte);
+        Ok(())
+    }
+
+    fn branch(&mut self, cond: u8, target: &Node) -> Result<(), Error> {
+        self.push(OP_BRANCH | cond);
+        self.address(target)
+    }
+
+    /// Append the machine code for `node`
+    #[allow(clippy::too_many_lines)]
+    fn encode(&mut self, node: &Node) -> Result<(), Error> {
+        let at = node.range();
+        self.start = self.segment.bytes.len();
+        match &**node {
+            Type::Move(a, b) => self.push(two(OP_MOVE, *a, *b)),
+            Type::Add(a, b) => self.push(two(OP_ADD, *a, *b)),
+            Type::Addc(a, b) => self.push(two(OP_ADDC, *a, *b)),
+            Type::Sub(a, b) => self.push(two(OP_SUB, *a, *b)),
+            Type::And(a, b) => self.push(two(OP_AND, *a, *b)),
+            Type::Or(a, b) => self.push(two(OP_OR, *a, *b)),
+            Type::Xor(a, b) => self.push(two(OP_XOR, *a, *b)),
+            Type::Cmp(a, b) => self.push(two(OP_CMP, *a, *b)),
+            Type::Not(r) => self.push(OP_NOT_NEG_INC_DEC | NOT | r),
+            Type::Neg(r) => self.push(OP_NOT_NEG_INC_DEC | NEG | r),
+            Type::Inc(r) => self.push(OP_NOT_NEG_INC_DEC | INC | r),
+            Type::Dec(r) => self.push(OP_NOT_NEG_INC_DEC | DEC | r),
+            Type::Shr(r) => self.push(OP_SHIFT | SHR | r),
+            Type::Shla(r) => self.push(OP_SHIFT | SHLA | r),
+            Type::Shra(r) => self.push(OP_SHIFT | SHRA | r),
+            Type::Rol(r) => self.push(OP_SHIFT | ROL | r),
+            Type::St(a, b) => self.push(two(OP_STORE, *a, *b)),
+            Type::Ld(a, b) => self.push(two(OP_LOAD, *a, *b)),
+            Type::Ldc(a, b) => self.push(two(OP_LOAD_C, *a, *b)),
+            Type::Push(r) => self.push(OP_STACK | PUSH | r),
+            Type::Pop(r) => self.push(OP_STACK | POP | r),
+            Type::Ldsa(r, offset) => {
+                self.push(OP_STACK | LDSA | r);
+                self.value(offset)?;
+            }
+            Type::Addsp(offset) => {
+                self.push(OP_STACK | ADDSP_SETSP_PUSHALL_POPALL | ADDSP);
+                self.value(offset)?;
+            }
+            Type::Setsp(to) => {
+                self.push(OP_STACK | ADDSP_SETSP_PUSHALL_POPALL | SETSP);
+                self.address(to)?;
+            }
+            Type::Pushall => self.push(OP_STACK | ADDSP_SETSP_PUSHALL_POPALL | PUSHALL),
+            Type::Popall => self.push(OP_STACK | ADDSP_SETSP_PUSHALL_POPALL | POPALL),
+            Type::Ldi(r, lit) => {
+                self.push(LDI_INTERRUPT | OP_LDI_0 | r);
+                self.value(lit)?;
+            }
+            Type::Halt => self.push(LDI_INTERRUPT | OP_HALT),
+            Type::Wait => self.push(LDI_INTERRUPT | OP_WAIT),
+            Type::Jsr(target) => {
+                self.push(LDI_INTERRUPT | OP_JSR);
+                self.address(target)?;
+            }
+            Type::Rts => self.push(LDI_INTERRUPT | OP_RTS),
+            Type::Ioi => self.push(LDI_INTERRUPT | OP_IOI),
+            Type::Rti => self.push(LDI_INTERRUPT | OP_RTI),
+            Type::Crc => self.push(LDI_INTERRUPT | OP_CRC),
+            Type::Osix(ps) => {
+                self.push(LDI_INTERRUPT | OP_OSIX);
+                self.push(*ps);
+            }
+            Type::Rand => self.push(LDI_INTERRUPT | OP_RAND),
+            Type::BeqBz(l) => self.branch(BEQ_BZ, l)?,
+            Type::BneBnz(l) => self.branch(BNE_BNZ, l)?,
+            Type::BhsBcs(l) => self.branch(BHS_BCS, l)?,
+            Type::BloBcc(l) => self.branch(BLO_BCC, l)?,
+            Type::Bmi(l) => self.branch(BMI, l)?,
+            Type::Bpl(l) => self.branch(BPL, l)?,
+            Type::Bvs(l) => self.branch(BVS, l)?,
+            Type::Bvc(l) => self.branch(BVC, l)?,
+            Type::Bhi(l) => self.branch(BHI, l)?,
+            Type::Bls(l) => self.branch(BLS, l)?,
+            Type::Bge(l) => self.branch(BGE, l)?,
+            Type::Blt(l) => self.branch(BLT, l)?,
+            Type::Bgt(l) => self.branch(BGT, l)?,
+            Type::Ble(l) => self.branch(BLE, l)?,
+            Type::Br(l) => self.branch(BR, l)?,
+            Type::Nop(l) => self.branch(NOP, l)?,
+            Type::Dc(data) => {
+                for item in data {
+                    self.value(item)?;
                 }
-                Node::Lsr(dest, src, operand) => {
-                    let ins = LSR | (src << 16) | (dest << 12) | encode(operand);
-                    storage.set(i, ins)?;
+            }
+            Type::Ds(amount) => {
+                let bytes = &mut self.segment.bytes;
+                bytes.resize(bytes.len() + *amount as usize, 0);
+            }
+            Type::Label(_) | Type::Entry(_) | Type::Asect(_) | Type::End => (),
+            Type::Signed(_)
+            | Type::Unsigned(_)
+            | Type::Constant(..)
+            | Type::Here
+            | Type::Negate(_)
+            | Type::Sum(..)
+            | Type::Difference(..) => return Err(Error::new(format!("Unexpected {}", **node), at)),
+        }
+        Ok(())
+    }
+}
+
+/// Encode an instruction with two register operands
+fn two(op: u8, a: Register, b: Register) -> u8 {
+    op | (a << 2) | b
+}
+
+/// Number of bytes `node` occupies once encoded
+pub(crate) fn size(node: &Type) -> usize {
+    match node {
+        Type::Ldsa(..)
+        | Type::Addsp(_)
+        | Type::Setsp(_)
+        | Type::Ldi(..)
+        | Type::Jsr(_)
+        | Type::Osix(_)
+        | Type::BeqBz(_)
+        | Type::BneBnz(_)
+        | Type::BhsBcs(_)
+        | Type::BloBcc(_)
+        | Type::Bmi(_)
+        | Type::Bpl(_)
+        | Type::Bvs(_)
+        | Type::Bvc(_)
+        | Type::Bhi(_)
+        | Type::Bls(_)
+        | Type::Bge(_)
+        | Type::Blt(_)
+        | Type::Bgt(_)
+        | Type::Ble(_)
+        | Type::Br(_)
+        | Type::Nop(_) => 2,
+        Type::Dc(data) => data.len(),
+        Type::Ds(amount) => *amount as usize,
+        Type::Label(_) | Type::Entry(_) | Type::Asect(_) | Type::End => 0,
+        _ => 1,
+    }
+}
+
+impl Assemble for Parser {
+    fn compile(&self) -> Result<Object, Error> {
+        let sections = self.sections();
+        let symbols = SymbolTable::build(&sections, self.externals(), self.constants(), self)?;
+        for warning in check(&sections, self) {
+            self.warn(warning);
+        }
+        let mut object = Object::new();
+
+        for sect in &sections {
+            let sect = sect.borrow();
+            let (placement, content) = match &*sect {
+                Section::Absolute { pos, content } => (Placement::Absolute(*pos), content),
+                Section::RSect { name, content } => (Placement::Relocatable(name.clone()), content),
+                Section::Template { .. } | Section::None => continue,
+            };
+            let start = match placement {
+                Placement::Absolute(pos) => pos as usize,
+                Placement::Relocatable(_) => 0,
+            };
+            let mut emitter = Emitter {
+                segment: Segment::new(placement),
+                symbols: &symbols,
+                start: 0,
+                warnings: Vec::new(),
+            };
+            for node in content {
+                let offset = emitter.segment.bytes.len();
+                emitter.encode(node)?;
+                emitter.segment.spans.push(Span {
+                    range: node.site(),
+                    offset,
+                    size: emitter.segment.bytes.len() - offset,
+                });
+                if start + emitter.segment.bytes.len() > MEM_SIZE {
+                    return Err(Error::new(
+                        "Section runs past the end of memory".to_string(),
+                        node.range(),
+                    ));
                 }
-                Node::Halt => {
-                    storage.set(i, HALT)?;
+                if let Type::Entry(name) = &**node {
+                    let location = symbols.resolve(name, node.range())?;
+                    object.entries.push((name.clone(), location.clone()));
+                    object.origins.insert(name.clone(), node.range());
                 }
             }
+            for warning in emitter.warnings {
+                self.warn(warning);
+            }
+            object.segments.push(emitter.segment);
         }
-        Ok(())
+
+        for (name, range) in self.externals() {
+            let used = object.segments.iter().any(|segment| {
+                segment
+                    .relocations
+                    .iter()
+                    .any(|reloc| reloc.target == Target::External(name.clone()))
+            });
+            if !used {
+                return Err(Error::new(
+                    format!("{} is declared ext but never used", name),
+                    *range,
+                ));
+            }
+            object.externals.push(name.clone());
+            object.origins.insert(name.clone(), *range);
+        }
+
+        Ok(object)
+    }
+
+    fn assemble(&self) -> Result<Image, Error> {
+        let mut linker = Linker::new();
+        linker.add("<input>".to_string(), self.compile()?);
+        Ok(linker.link()?.image)
     }
 }
@@ -0,0 +1,516 @@
+use belgium::reformat;
+use belgium::Allowed;
+use belgium::Assemble;
+use belgium::ChangeEvent;
+use belgium::Disassembler;
+use belgium::Format;
+use belgium::Linker;
+use belgium::Listing;
+use belgium::Machine;
+use belgium::Observer;
+use belgium::Parser;
+use belgium::Sources;
+use belgium::SymbolTable;
+use belgium::MAX_ERRORS;
+use belgium::{Object, OBJECT_HEADER};
+use belgium::{Response, COUNTER, SP, STATUS};
+
+use std::env;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+
+use getopts::Options;
+
+struct RChange {
+    verbose: bool,
+}
+
+impl Observer<ChangeEvent> for RChange {
+    fn notify(&self, evt: ChangeEvent) {
+        match evt.idx {
+            COUNTER => {
+                if self.verbose {
+                    println!("Counter     to 0x{:02X}", evt.val)
+                }
+            }
+            STATUS => {
+                if self.verbose {
+                    println!("Status      to 0b{:08b}", evt.val)
+                }
+            }
+            SP => {
+                if self.verbose {
+                    println!("Stack       to 0x{:02X}", evt.val)
+                }
+            }
+            _ => println!(
+                "R{:02}         to 0x{:02X} ({}, {})",
+                evt.idx, evt.val, evt.val, evt.val as i8
+            ),
+        }
+    }
+}
+
+struct MChange;
+
+impl Observer<ChangeEvent> for MChange {
+    fn notify(&self, evt: ChangeEvent) {
+        println!(
+            "Memory 0x{:02X} to 0x{:02X} ({})",
+            evt.idx, evt.val, evt.val
+        );
+    }
+}
+
+/// A file to be linked
+struct Unit {
+    object: Object,
+    /// The source files and symbols, if it was assembled
+    source: Option<(Sources, SymbolTable)>,
+    /// Warnings its comments turned off
+    allowed: Allowed,
+}
+
+/// Read `path` as an object, assembling it first if it's source, with
+/// included files searched for in `include` as well and each of `defines`
+/// set
+fn unit(
+    path: &Path,
+    symbols: bool,
+    max_errors: usize,
+    include: &[String],
+    defines: &[(String, i32)],
+) -> Option<Unit> {
+    // Check the file exists
+    if !path.exists() {
+        // It didn't
+        println!("{} doesn't exist", path.display());
+        return None;
+    }
+
+    // Read the file into a string
+    let source = match read_to_string(path) {
+        Ok(source) => source,
+        // Or not...
+        Err(e) => {
+            println!("Can't read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    if source.starts_with(OBJECT_HEADER) {
+        return match Object::read(&source) {
+            Ok(object) => Some(Unit {
+                object,
+                source: None,
+                allowed: Allowed::default(),
+            }),
+            Err(err) => {
+                println!("{}: {}", path.display(), err);
+                None
+            }
+        };
+    }
+
+    // Parse the program
+    let mut sources = Sources::new();
+    sources.add(path.to_path_buf(), source, None);
+    for dir in include {
+        sources.search(dir.into());
+    }
+    let mut parser = Parser::new(sources);
+    parser.set_max_errors(max_errors);
+    for (name, value) in defines {
+        if let Err(err) = parser.define(name, *value) {
+            err.print(Some(&*parser));
+            return None;
+        }
+    }
+    let object = parser
+        .node()
+        .and_then(|()| parser.compile().map_err(|err| vec![err]));
+    for warning in parser.warnings() {
+        eprint!("Warning: ");
+        warning.print(Some(&*parser));
+    }
+    match object {
+        Ok(object) => {
+            let table = SymbolTable::build(
+                &parser.sections(),
+                parser.externals(),
+                parser.constants(),
+                &parser,
+            )
+            .ok();
+            if let (true, Some(table)) = (symbols, &table) {
+                print!("{}", table);
+            }
+            let allowed = parser.allowed().clone();
+            Some(Unit {
+                object,
+                source: table.map(|table| (parser.into_sources(), table)),
+                allowed,
+            })
+        }
+        Err(errors) => {
+            for err in errors {
+                err.print(Some(&*parser));
+            }
+            None
+        }
+    }
+}
+
+/// `belgium fmt`, lay out each file in place
+fn fmt(program: &str, arguments: &[String]) {
+    let mut opts = Options::new();
+    opts.optflag(
+        "",
+        "check",
+        "list files that aren't laid out instead of changing them",
+    );
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(arguments) {
+        Ok(m) => m,
+        Err(f) => {
+            println!("{}", f);
+            return;
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        let brief = format!("Usage: {} fmt [options] FILE...", program);
+        print!("{}", opts.usage(&brief));
+        return;
+    }
+
+    let mut unformatted = false;
+    for input in &matches.free {
+        let source = match read_to_string(input) {
+            Ok(source) => source,
+            Err(err) => {
+                println!("Failed to read {}: {}", input, err);
+                unformatted = true;
+                continue;
+            }
+        };
+        let formatted = match reformat(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                let mut sources = Sources::new();
+                sources.add(input.into(), source, None);
+                err.print(Some(&sources));
+                unformatted = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if matches.opt_present("check") {
+            println!("{}", input);
+            unformatted = true;
+        } else if let Err(err) = write(input, formatted) {
+            println!("Failed to write {}: {}", input, err);
+            unformatted = true;
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+/// Parse `-D NAME=VALUE`, where the value is decimal or `0x` prefixed hex
+/// and is 1 if it's left out
+fn define(text: &str) -> Option<(String, i32)> {
+    let (name, value) = text.split_once('=').unwrap_or((text, "1"));
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    Some((name.to_string(), if negative { -value } else { value }))
+}
+
+// The entry point
+fn main() {
+    // Fetch the arguments into an array
+    let arguments: Vec<String> = env::args().collect();
+    let program = arguments[0].clone();
+
+    if arguments.get(1).map(String::as_str) == Some("fmt") {
+        fmt(&program, &arguments[2..]);
+        return;
+    }
+
+    // Setup the argument parser
+    let mut opts = Options::new();
+    opts.optflag(
+        "v",
+        "verbose",
+        "show system register changes (overrides -c)",
+    );
+    opts.optflag("c", "reg-changed", "show changes to registers");
+    opts.optflag("m", "mem-changed", "show changes to memory");
+    opts.optflag("i", "dump-inital", "show inital state of memory");
+    opts.optflag("f", "dump-final", "show final state of memory");
+    opts.optflag("r", "registers", "show final state of registers");
+    opts.optflag("s", "symbols", "show the symbol table");
+    opts.optflag(
+        "",
+        "object",
+        "write a relocatable object (to -o or FILE.obj) instead of running",
+    );
+    opts.optflag("", "map", "show where each section was placed");
+    opts.optflag("d", "disassemble", "show the linked program disassembled");
+    opts.optflag(
+        "l",
+        "listing",
+        "write a listing of each source file (to FILE.lst)",
+    );
+    opts.optflag("h", "help", "print this help menu");
+    opts.optmulti(
+        "D",
+        "define",
+        "set NAME to VALUE (or 1) for .if",
+        "NAME[=VALUE]",
+    );
+    opts.optmulti("I", "include", "also look for included files in DIR", "DIR");
+    opts.optopt(
+        "",
+        "max-errors",
+        &format!("stop after this many errors (default {})", MAX_ERRORS),
+        "N",
+    );
+    opts.optopt(
+        "o",
+        "",
+        "write the assembled image instead of running",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "format",
+        &format!(
+            "format of the image written by -o: {} (guessed from NAME otherwise)",
+            Format::NAMES
+        ),
+        "FORMAT",
+    );
+
+    // Try and parse the arguments
+    let matches = match opts.parse(&arguments[1..]) {
+        // Store the result
+        Ok(m) => m,
+        // Something went wrong
+        Err(f) => {
+            // Display the message
+            println!("{}", f);
+            // Quit early
+            return;
+        }
+    };
+
+    if matches.opt_present("h") {
+        let brief = format!("Usage: {} [options] FILE...", program);
+        print!("{}", opts.usage(&brief));
+        return;
+    }
+
+    // If a file wasn't passed
+    if matches.free.is_empty() {
+        println!("Expected a file");
+        // Exit
+        return;
+    }
+
+    let max_errors = match matches.opt_str("max-errors").map(|max| max.parse()) {
+        Some(Ok(max)) => max,
+        Some(Err(err)) => {
+            println!("Bad --max-errors: {}", err);
+            return;
+        }
+        None => MAX_ERRORS,
+    };
+
+    let mut defines = Vec::new();
+    for text in matches.opt_strs("D") {
+        match define(&text) {
+            Some(define) => defines.push(define),
+            None => {
+                println!("Bad -D {}, expected NAME=VALUE", text);
+                return;
+            }
+        }
+    }
+
+    // Assemble (or read) every file into an object
+    let mut units = Vec::with_capacity(matches.free.len());
+    for input in &matches.free {
+        match unit(
+            Path::new(input),
+            matches.opt_present("s"),
+            max_errors,
+            &matches.opt_strs("I"),
+            &defines,
+        ) {
+            Some(unit) => units.push((input.clone(), unit)),
+            None => return,
+        }
+    }
+
+    if matches.opt_present("object") {
+        for (input, Unit { object, .. }) in &units {
+            let output = match matches.opt_str("o") {
+                Some(output) if units.len() == 1 => output,
+                _ => format!("{}.obj", Path::new(input).with_extension("").display()),
+            };
+            if let Err(err) = write(&output, object.to_string()) {
+                println!("Failed to write {}: {}", output, err);
+            }
+        }
+        return;
+    }
+
+    // Link them together into memory, with every unit's files numbered
+    // apart so errors can be shown against the right one
+    let mut linker = Linker::new();
+    let mut sources = Sources::new();
+    let mut allowed = Allowed::default();
+    for (input, unit) in &units {
+        let mut object = unit.object.clone();
+        if let Some((unit_sources, _)) = &unit.source {
+            let base = sources.append(unit_sources);
+            object.renumber(base);
+            allowed.append(&unit.allowed, base);
+        }
+        linker.add(input.clone(), object);
+    }
+    let linked = match linker.link() {
+        Ok(linked) => linked,
+        // Opps error
+        Err(err) => {
+            err.print(Some(&sources));
+            return;
+        }
+    };
+
+    for warning in &linked.warnings {
+        if !allowed.allows(warning) {
+            eprint!("Warning: ");
+            warning.print(Some(&sources));
+        }
+    }
+
+    if matches.opt_present("map") {
+        print!("{}", linked);
+    }
+
+    if matches.opt_present("d") {
+        let mut disassembler = Disassembler::new(&linked.image);
+        for (input, unit) in &units {
+            if let Some((_, symbols)) = &unit.source {
+                disassembler.symbols(input, symbols, &linked);
+            }
+        }
+        print!("{}", disassembler);
+    }
+
+    if matches.opt_present("l") {
+        for (input, unit) in &units {
+            if let Some((source, symbols)) = &unit.source {
+                let listing = Listing::new(input, source, &unit.object, symbols, &linked);
+                let output = format!("{}.lst", Path::new(input).with_extension("").display());
+                if let Err(err) = write(&output, listing.to_string()) {
+                    println!("Failed to write {}: {}", output, err);
+                }
+            }
+        }
+    }
+
+    let image = linked.image;
+
+    if let Some(output) = matches.opt_str("o") {
+        let format = match matches.opt_str("format") {
+            Some(name) => match Format::named(&name) {
+                Some(format) => format,
+                None => {
+                    println!("Unknown format {}, expected {}", name, Format::NAMES);
+                    return;
+                }
+            },
+            None => Format::guess(Path::new(&output)),
+        };
+        if let Err(err) = write(&output, format.write(&image)) {
+            println!("Failed to write {}: {}", output, err);
+        }
+        return;
+    }
+
+    let mut machine = Machine::new();
+
+    // Declared outside the if to keep a local reference
+    let rc: Rc<dyn Observer<ChangeEvent>> = Rc::new(RChange {
+        verbose: matches.opt_present("v"),
+    });
+    if matches.opt_present("v") || matches.opt_present("c") {
+        machine.add_reg_observer(Rc::downgrade(&rc));
+    }
+
+    let rc: Rc<dyn Observer<ChangeEvent>> = Rc::new(MChange {});
+    if matches.opt_present("m") {
+        machine.add_mem_observer(Rc::downgrade(&rc));
+    }
+
+    machine.load(&image);
+
+    if matches.opt_present("i") {
+        for (i, v) in machine.iter_mem() {
+            println!("0x{:02X}: 0x{:02X} ({:4}, {:3})", i, v, v as i8, v);
+        }
+    }
+
+    loop {
+        match machine.step(None) {
+            Ok(Response::Halt) => {
+                println!("stop on halt");
+                break;
+            }
+            Ok(Response::Wait) => {
+                println!("stop on wait");
+                break;
+            }
+            Ok(_) => continue,
+            Err(Response::UnknownInstruction) => {
+                println!("Bad Instruction");
+                break;
+            }
+            Err(Response::BadRegister) => {
+                println!("Bad Register");
+                break;
+            }
+            Err(_) => continue,
+        }
+    }
+
+    if matches.opt_present("f") {
+        for (i, v) in machine.iter_mem() {
+            println!("0x{:02X}: 0x{:02X} ({:4}, {:3})", i, v, v as i8, v);
+        }
+    }
+
+    // Show the end state of the registers
+    if matches.opt_present("r") {
+        for i in 0..4 {
+            if let Ok(v) = machine.reg(i) {
+                println!("R{}: 0x{:02X} ({:4}, {:3})", i, v, v as i8, v);
+            }
+        }
+    }
+}
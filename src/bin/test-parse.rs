@@ -1,4 +1,4 @@
-use belgium::{Input, Parser};
+use belgium::{Parser, Sources};
 
 fn main() {
     let test = "asect  0x00
@@ -24,11 +24,12 @@ res:	ds	1		# one byte reserved for the result
 rsect idk
 end";
 
-    let input = Input::from(test.to_string());
-    let mut parser = Parser::new(input);
+    let mut parser = Parser::new(Sources::from(test.to_string()));
 
-    if let Err(err) = parser.node() {
-        err.print(Some(&*parser));
+    if let Err(errors) = parser.node() {
+        for err in errors {
+            err.print(Some(&*parser));
+        }
     } else {
         for sect in parser.sections() {
             println!("{}", sect.borrow());
@@ -1,5 +1,5 @@
-use belgium::Input;
-use belgium::{Token, Type};
+use belgium::Type;
+use belgium::{Input, Sources};
 
 fn main() {
     let test = "
@@ -78,7 +78,7 @@ fn main() {
                 _ => println!("{:?}", *node),
             },
             Err(err) => {
-                err.print(Some(&input));
+                err.print(Some(&Sources::from(test.to_string())));
                 break;
             }
         }
@@ -1,12 +1,19 @@
 use belgium::ChangeEvent;
+use belgium::Disassembler;
+use belgium::Format;
+use belgium::Image;
+use belgium::Linker;
 use belgium::Machine;
 use belgium::Observer;
+use belgium::Sources;
+use belgium::{Object, OBJECT_HEADER};
 use belgium::{Response, COUNTER, SP, STATUS};
 
 use std::env;
 use std::fs::read;
 use std::path::Path;
 use std::rc::Rc;
+use std::str;
 
 use getopts::Options;
 
@@ -51,6 +58,43 @@ impl Observer<ChangeEvent> for MChange {
     }
 }
 
+/// Load `data`, read from `path`, into memory `base` bytes up
+fn load(path: &Path, data: &[u8], base: u8, format: Option<Format>) -> Option<Image> {
+    let text = str::from_utf8(data).unwrap_or("");
+    let result = if format.is_none() && text.starts_with(OBJECT_HEADER) {
+        if base != 0 {
+            println!("Objects are placed by the linker, --base can't move them");
+            return None;
+        }
+        Object::read(text).and_then(|object| {
+            let mut linker = Linker::new();
+            linker.add(path.display().to_string(), object);
+            linker.link().map(|linked| linked.image)
+        })
+    } else {
+        format
+            .unwrap_or_else(|| Format::detect(path, data))
+            .read(data, base)
+    };
+    match result {
+        Ok(image) => Some(image),
+        Err(err) => {
+            let mut sources = Sources::new();
+            sources.add(path.to_path_buf(), text.to_string(), None);
+            err.print(Some(&sources));
+            None
+        }
+    }
+}
+
+/// Parse a decimal or `0x` prefixed address
+fn address(text: &str) -> Option<u8> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
 // The entry point
 fn main() {
     // Fetch the arguments into an array
@@ -69,7 +113,15 @@ fn main() {
     opts.optflag("i", "dump-inital", "show inital state of memory");
     opts.optflag("f", "dump-final", "show final state of memory");
     opts.optflag("r", "registers", "show final state of registers");
+    opts.optflag("d", "disassemble", "show the program disassembled");
     opts.optflag("h", "help", "print this help menu");
+    opts.optopt("b", "base", "load the image at this address", "ADDR");
+    opts.optopt(
+        "",
+        "format",
+        &format!("format of FILE: {} (detected otherwise)", Format::NAMES),
+        "FORMAT",
+    );
 
     // Try and parse the arguments
     let matches = match opts.parse(&arguments[1..]) {
@@ -100,12 +152,39 @@ fn main() {
         matches.free[0].clone()
     };
 
+    let base = match matches.opt_str("b") {
+        Some(base) => match address(&base) {
+            Some(base) => base,
+            None => {
+                println!("Expected a base address from 0 to 0xFF, got {}", base);
+                return;
+            }
+        },
+        None => 0,
+    };
+
+    let format = match matches.opt_str("format") {
+        Some(name) => match Format::named(&name) {
+            Some(format) => Some(format),
+            None => {
+                println!("Unknown format {}, expected {}", name, Format::NAMES);
+                return;
+            }
+        },
+        None => None,
+    };
+
     // Check the file exists
     let path = Path::new(&input);
     if path.exists() {
         // Read the file into a string
         match read(path) {
-            Ok(program) => {
+            Ok(data) => {
+                let program = match load(path, &data, base, format) {
+                    Some(program) => program,
+                    None => return,
+                };
+
                 let mut machine = Machine::new();
 
                 // Declared outside the if to keep a local reference
@@ -125,6 +204,10 @@ fn main() {
                     machine.set_mem(i as u8, *b);
                 }
 
+                if matches.opt_present("d") {
+                    print!("{}", Disassembler::new(&program));
+                }
+
                 if matches.opt_present("i") {
                     for (i, v) in machine.iter_mem() {
                         println!("0x{:04X}: 0x{:08X} {:10}", i, v, v);
@@ -0,0 +1,146 @@
+use crate::node::{Label, Node, Type};
+use crate::opcodes::{
+    BEQ_BZ, BGE, BGT, BHI, BHS_BCS, BLE, BLO_BCC, BLS, BLT, BMI, BNE_BNZ, BPL, BR, BVC, BVS, NOP,
+};
+use crate::token::Range;
+
+/// Where we are within an `if` or `while`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Part {
+    /// Before `is` or `stays`, setting up the flags
+    Condition,
+    /// Running the body
+    Body,
+    /// After `else`
+    Else,
+}
+
+/// A construct that has been opened but not yet closed
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub kind: Kind,
+    /// Used to name this block's labels
+    pub id: usize,
+    /// The opening keyword
+    pub range: Range,
+}
+
+/// The structured constructs, lowered to branches between generated labels
+/// that start with `$` so they can't clash with the source
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    If(Part),
+    While(Part),
+    Do,
+}
+
+impl Block {
+    /// The keyword that opened this block
+    #[must_use]
+    pub fn opener(&self) -> &'static str {
+        match self.kind {
+            Kind::If(_) => "if",
+            Kind::While(_) => "while",
+            Kind::Do => "do",
+        }
+    }
+
+    /// The keyword expected next
+    #[must_use]
+    pub fn expecting(&self) -> &'static str {
+        match self.kind {
+            Kind::If(Part::Condition) => "is",
+            Kind::If(_) => "fi",
+            Kind::While(Part::Condition) => "stays",
+            Kind::While(_) => "wend",
+            Kind::Do => "until",
+        }
+    }
+
+    /// A label belonging to this block, such as `$fi3`
+    #[must_use]
+    pub fn label(&self, name: &str) -> Label {
+        format!("${}{}", name, self.id)
+    }
+
+    /// Where `break` jumps to, `None` if this isn't a loop body
+    #[must_use]
+    pub fn exit(&self) -> Option<Label> {
+        match self.kind {
+            Kind::While(Part::Body) => Some(self.label("wend")),
+            Kind::Do => Some(self.label("od")),
+            Kind::If(_) | Kind::While(_) => None,
+        }
+    }
+
+    /// Where `continue` jumps to, `None` if this isn't a loop body
+    #[must_use]
+    pub fn next(&self) -> Option<Label> {
+        match self.kind {
+            Kind::While(Part::Body) => Some(self.label("while")),
+            Kind::Do => Some(self.label("until")),
+            Kind::If(_) | Kind::While(_) => None,
+        }
+    }
+}
+
+/// The branch variant tested by condition `name`, as in `is mi`
+#[must_use]
+pub fn condition(name: &str) -> Option<u8> {
+    match name {
+        "eq" | "z" => Some(BEQ_BZ),
+        "ne" | "nz" => Some(BNE_BNZ),
+        "hs" | "cs" => Some(BHS_BCS),
+        "lo" | "cc" => Some(BLO_BCC),
+        "mi" => Some(BMI),
+        "pl" => Some(BPL),
+        "vs" => Some(BVS),
+        "vc" => Some(BVC),
+        "hi" => Some(BHI),
+        "ls" => Some(BLS),
+        "ge" => Some(BGE),
+        "lt" => Some(BLT),
+        "gt" => Some(BGT),
+        "le" => Some(BLE),
+        _ => None,
+    }
+}
+
+/// The condition that holds exactly when `cond` doesn't
+///
+/// Branch variants come in pairs that differ only in the lowest bit
+#[must_use]
+pub fn inverse(cond: u8) -> u8 {
+    cond ^ 1
+}
+
+/// A reference to generated label `name`, used at `at`
+#[must_use]
+pub fn jump(name: Label, at: Range) -> Node {
+    Node::new(Type::Label(name), at)
+}
+
+/// A branch node testing `cond`
+#[must_use]
+pub fn branch(cond: u8, target: Node) -> Type {
+    let target = Box::new(target);
+    match cond {
+        BEQ_BZ => Type::BeqBz(target),
+        BNE_BNZ => Type::BneBnz(target),
+        BHS_BCS => Type::BhsBcs(target),
+        BLO_BCC => Type::BloBcc(target),
+        BMI => Type::Bmi(target),
+        BPL => Type::Bpl(target),
+        BVS => Type::Bvs(target),
+        BVC => Type::Bvc(target),
+        BHI => Type::Bhi(target),
+        BLS => Type::Bls(target),
+        BGE => Type::Bge(target),
+        BLT => Type::Blt(target),
+        BGT => Type::Bgt(target),
+        BLE => Type::Ble(target),
+        BR => Type::Br(target),
+        NOP => Type::Nop(target),
+        _ => unreachable!("Branch variants are 4 bits"),
+    }
+}
@@ -0,0 +1,194 @@
+use crate::assemble::size;
+use crate::control::branch;
+use crate::image::Image;
+use crate::link::Linked;
+use crate::machine::MEM_SIZE;
+use crate::node::{Node, Type};
+use crate::op1;
+use crate::op2;
+use crate::opcodes::{
+    ADDSP, DEC, LDI_INTERRUPT, LDSA, NEG, NOT, OPERATION, OP_ADD, OP_ADDC, OP_AND, OP_BRANCH,
+    OP_CMP, OP_CRC, OP_HALT, OP_IOI, OP_JSR, OP_LDI_0, OP_LDI_3, OP_LOAD, OP_LOAD_C, OP_MOVE,
+    OP_NOT_NEG_INC_DEC, OP_OR, OP_OSIX, OP_RAND, OP_RTI, OP_RTS, OP_SHIFT, OP_STACK, OP_STORE,
+    OP_SUB, OP_WAIT, OP_XOR, POP, PUSH, PUSHALL, SETSP, SHLA, SHR, SHRA,
+};
+use crate::symbols::SymbolTable;
+use crate::token::{Point, Range};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// One instruction, or a byte that isn't one, found in memory
+pub struct Decoded {
+    pub address: u8,
+    pub bytes: Vec<u8>,
+    /// A label naming `address`, if one is known
+    pub label: Option<String>,
+    /// The instruction, or `dc` for anything that doesn't decode. It has no
+    /// source so its range is empty
+    pub node: Node,
+}
+
+/// Decodes the used parts of a memory image
+pub struct Disassembler<'a> {
+    image: &'a Image,
+    labels: HashMap<u8, String>,
+}
+
+impl<'a> Disassembler<'a> {
+    #[must_use]
+    pub fn new(image: &'a Image) -> Self {
+        Self {
+            image,
+            labels: HashMap::new(),
+        }
+    }
+
+    /// Name addresses with the symbols of `unit`, as placed by `linked`
+    ///
+    /// Names written in the source win over ones the assembler generated
+    pub fn symbols(&mut self, unit: &str, symbols: &SymbolTable, linked: &Linked) {
+        for (name, symbol) in symbols.sorted() {
+            if let Some(addr) = linked.address(unit, symbol.location()) {
+                match self.labels.get(&addr) {
+                    Some(existing) if !existing.contains('$') => (),
+                    _ => {
+                        self.labels.insert(addr, name.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every used byte, decoded from the lowest address up
+    #[must_use]
+    pub fn decode(&self) -> Vec<Decoded> {
+        let mut decoded = Vec::new();
+        let mut addr = 0;
+        while addr < MEM_SIZE {
+            #[allow(clippy::cast_possible_truncation)]
+            let at = addr as u8;
+            if !self.image.used(at) {
+                addr += 1;
+                continue;
+            }
+            let first = self.image.get(at);
+            // The operand has to be part of the program too
+            let operand = if addr + 1 < MEM_SIZE && self.image.used(at.wrapping_add(1)) {
+                Some(self.image.get(at.wrapping_add(1)))
+            } else {
+                None
+            };
+            let data = self
+                .instruction(first, operand)
+                .unwrap_or_else(|| Type::Dc(vec![Node::new(Type::Unsigned(first), nowhere())]));
+            let size = size(&data);
+            let node = Node::new(data, nowhere());
+            decoded.push(Decoded {
+                address: at,
+                bytes: self.image[addr..addr + size].to_vec(),
+                label: self.labels.get(&at).cloned(),
+                node,
+            });
+            addr += size;
+        }
+        decoded
+    }
+
+    /// A jump target, named if there's a label there
+    fn target(&self, value: u8, at: Range) -> Node {
+        match self.labels.get(&value) {
+            Some(name) => Node::new(Type::Label(name.clone()), at),
+            None => Node::new(Type::Unsigned(value), at),
+        }
+    }
+
+    /// The instruction starting with `byte`, `None` if it isn't one or
+    /// needs an `operand` that isn't there
+    fn instruction(&self, byte: u8, operand: Option<u8>) -> Option<Type> {
+        let a = op1!(byte);
+        let b = op2!(byte);
+        let variant = byte & 0b0000_1100;
+        let range = nowhere();
+        let value = || operand.map(|value| Box::new(Node::new(Type::Unsigned(value), range)));
+        Some(match byte & OPERATION {
+            OP_MOVE => Type::Move(a, b),
+            OP_ADD => Type::Add(a, b),
+            OP_ADDC => Type::Addc(a, b),
+            OP_SUB => Type::Sub(a, b),
+            OP_AND => Type::And(a, b),
+            OP_OR => Type::Or(a, b),
+            OP_XOR => Type::Xor(a, b),
+            OP_CMP => Type::Cmp(a, b),
+            OP_NOT_NEG_INC_DEC => match variant {
+                NOT => Type::Not(b),
+                NEG => Type::Neg(b),
+                DEC => Type::Dec(b),
+                _ => Type::Inc(b),
+            },
+            OP_SHIFT => match variant {
+                SHR => Type::Shr(b),
+                SHLA => Type::Shla(b),
+                SHRA => Type::Shra(b),
+                _ => Type::Rol(b),
+            },
+            OP_STORE => Type::St(a, b),
+            OP_LOAD => Type::Ld(a, b),
+            OP_LOAD_C => Type::Ldc(a, b),
+            OP_STACK => match variant {
+                PUSH => Type::Push(b),
+                POP => Type::Pop(b),
+                LDSA => Type::Ldsa(b, value()?),
+                _ => match b {
+                    ADDSP => Type::Addsp(value()?),
+                    SETSP => Type::Setsp(value()?),
+                    PUSHALL => Type::Pushall,
+                    _ => Type::Popall,
+                },
+            },
+            LDI_INTERRUPT => match byte & 0b0000_1111 {
+                OP_LDI_0..=OP_LDI_3 => Type::Ldi(b, value()?),
+                OP_HALT => Type::Halt,
+                OP_WAIT => Type::Wait,
+                OP_JSR => Type::Jsr(Box::new(self.target(operand?, range))),
+                OP_RTS => Type::Rts,
+                OP_IOI => Type::Ioi,
+                OP_RTI => Type::Rti,
+                OP_CRC => Type::Crc,
+                OP_OSIX => Type::Osix(operand?),
+                OP_RAND => Type::Rand,
+                _ => return None,
+            },
+            OP_BRANCH => branch(byte & 0b0000_1111, self.target(operand?, range)),
+            _ => unreachable!("Operations are 4 bits"),
+        })
+    }
+}
+
+/// The empty range given to decoded nodes, before the first line
+fn nowhere() -> Range {
+    Range::new(Point::new(0, 0), Point::new(0, 0))
+}
+
+impl fmt::Display for Disassembler<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for decoded in self.decode() {
+            if let Some(label) = &decoded.label {
+                writeln!(f, "{}:", label)?;
+            }
+            let bytes: Vec<_> = decoded
+                .bytes
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            writeln!(
+                f,
+                "0x{:02X}  {:8}  {}",
+                decoded.address,
+                bytes.join(" "),
+                *decoded.node
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use crate::node::{Node, Type};
+use crate::stream::Error;
+use crate::token::Range;
+use crate::warning::{Code, Warning};
+
+/// The result of an expression, which may still depend on where sections
+/// are placed
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// A plain number
+    Absolute(i32),
+    /// An offset from the start of the named rsect
+    Relative(String, i32),
+    /// An offset from a symbol provided by another unit
+    External(String, i32),
+}
+
+/// What names mean while evaluating
+pub trait Scope {
+    /// # Errors
+    ///
+    /// Will return `Err` if `name`, used at `at`, can't be used here
+    fn symbol(&self, name: &str, at: Range) -> Result<Value, Error>;
+
+    /// The address of the instruction being assembled
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there isn't a current instruction
+    fn here(&self, at: Range) -> Result<Value, Error>;
+}
+
+/// Expressions that have to be known while parsing, such as `asect`
+/// addresses, can only use numbers
+pub struct Constants;
+
+impl Scope for Constants {
+    fn symbol(&self, name: &str, at: Range) -> Result<Value, Error> {
+        Err(Error::new(
+            format!("{} isn't known yet, only constants can be used here", name),
+            at,
+        ))
+    }
+
+    fn here(&self, at: Range) -> Result<Value, Error> {
+        Err(Error::new(
+            "There is no current address here".to_string(),
+            at,
+        ))
+    }
+}
+
+/// # Errors
+///
+/// Will return `Err` spanning the offending part of `node` if it uses an
+/// unknown symbol or combines addresses in a way that can't be relocated
+pub fn evaluate(node: &Node, scope: &dyn Scope) -> Result<Value, Error> {
+    match &**node {
+        Type::Unsigned(num) => Ok(Value::Absolute(i32::from(*num))),
+        Type::Signed(num) => Ok(Value::Absolute(i32::from(*num))),
+        Type::Constant(_, value) => Ok(Value::Absolute(*value)),
+        Type::Label(name) => scope.symbol(name, node.range()),
+        Type::Here => scope.here(node.range()),
+        Type::Negate(inner) => match evaluate(inner, scope)? {
+            Value::Absolute(num) => Ok(Value::Absolute(num.wrapping_neg())),
+            _ => Err(Error::new(
+                format!("Can't negate the address {}", ***inner),
+                node.range(),
+            )),
+        },
+        Type::Sum(a, b) => match (evaluate(a, scope)?, evaluate(b, scope)?) {
+            (Value::Absolute(a), Value::Absolute(b)) => Ok(Value::Absolute(a.wrapping_add(b))),
+            (Value::Relative(sect, a), Value::Absolute(b))
+            | (Value::Absolute(b), Value::Relative(sect, a)) => {
+                Ok(Value::Relative(sect, a.wrapping_add(b)))
+            }
+            (Value::External(name, a), Value::Absolute(b))
+            | (Value::Absolute(b), Value::External(name, a)) => {
+                Ok(Value::External(name, a.wrapping_add(b)))
+            }
+            _ => Err(Error::new(
+                "Can't add two relocatable addresses".to_string(),
+                node.range(),
+            )),
+        },
+        Type::Difference(a, b) => match (evaluate(a, scope)?, evaluate(b, scope)?) {
+            (Value::Absolute(a), Value::Absolute(b)) => Ok(Value::Absolute(a.wrapping_sub(b))),
+            (Value::Relative(sect, a), Value::Absolute(b)) => {
+                Ok(Value::Relative(sect, a.wrapping_sub(b)))
+            }
+            (Value::External(name, a), Value::Absolute(b)) => {
+                Ok(Value::External(name, a.wrapping_sub(b)))
+            }
+            // The distance between two labels in one rsect doesn't move
+            (Value::Relative(a_sect, a), Value::Relative(b_sect, b)) if a_sect == b_sect => {
+                Ok(Value::Absolute(a.wrapping_sub(b)))
+            }
+            _ => Err(Error::new(
+                "Can only subtract addresses in the same section".to_string(),
+                node.range(),
+            )),
+        },
+        other => Err(Error::new(
+            format!("Expected a constant, got {}", other),
+            node.range(),
+        )),
+    }
+}
+
+/// Evaluate `node` while parsing, when only numbers and constants are known
+///
+/// # Errors
+///
+/// Will return `Err` if `node` uses a label or the current address
+pub fn constant(node: &Node) -> Result<i32, Error> {
+    match evaluate(node, &Constants)? {
+        Value::Absolute(value) => Ok(value),
+        _ => Err(Error::new(
+            format!("Expected a constant, got {}", **node),
+            node.range(),
+        )),
+    }
+}
+
+/// Fit `value` into a byte, adding a warning if it wraps
+///
+/// Anything from -128 to 255 fits, negative values as two's complement
+pub fn byte(value: i32, at: Range, warnings: &mut Vec<Warning>) -> u8 {
+    if !(-128..=255).contains(&value) {
+        warnings.push(Warning::new(
+            Code::Wrap,
+            format!(
+                "{} doesn't fit in a byte and wraps around to {}",
+                value,
+                value & 0xFF
+            ),
+            at,
+        ));
+    }
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let byte = value as u8;
+    byte
+}
+
+/// Fit `value`, an address or count, into a byte, adding a warning if it's
+/// negative and so only fits as two's complement
+pub fn unsigned(value: i32, at: Range, warnings: &mut Vec<Warning>) -> u8 {
+    if (-128..0).contains(&value) {
+        warnings.push(Warning::new(
+            Code::Signed,
+            format!("{} is negative, it's used as {}", value, value & 0xFF),
+            at,
+        ));
+    }
+    byte(value, at, warnings)
+}
@@ -0,0 +1,394 @@
+use crate::image::Image;
+use crate::machine::MEM_SIZE;
+use crate::stream::Error;
+use crate::token::{Point, Range};
+
+use std::fmt::Write;
+use std::path::Path;
+use std::str;
+
+/// Bytes per record or line
+const LINE: usize = 16;
+
+/// Identical bytes needed before Logisim output uses `N*value`
+const RUN: usize = 4;
+
+/// The image formats other tools use, Intel HEX and `$readmemh` only hold
+/// the bytes that were used
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Plain bytes from address 0
+    Raw,
+    /// Logisim `v2.0 raw`, as loaded into a RAM or ROM component
+    Logisim,
+    /// Intel HEX records
+    IntelHex,
+    /// Verilog `$readmemh` text
+    Memh,
+    /// A Rust `const` array
+    Rust,
+    /// A C array
+    C,
+}
+
+impl Format {
+    /// The names accepted by `--format`
+    pub const NAMES: &'static str = "raw, logisim, ihex, memh, rust or c";
+
+    /// The format called `name`
+    #[must_use]
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_ref() {
+            "raw" | "bin" => Some(Self::Raw),
+            "logisim" => Some(Self::Logisim),
+            "ihex" | "hex" => Some(Self::IntelHex),
+            "memh" | "readmemh" => Some(Self::Memh),
+            "rust" | "rs" => Some(Self::Rust),
+            "c" => Some(Self::C),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the extension of `path`, defaulting to raw
+    #[must_use]
+    pub fn guess(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("img") => Self::Logisim,
+            Some("hex" | "ihex") => Self::IntelHex,
+            Some("mem" | "memh") => Self::Memh,
+            Some("rs") => Self::Rust,
+            Some("c" | "h") => Self::C,
+            _ => Self::Raw,
+        }
+    }
+
+    /// Work out the format of `data`, from its contents where they give it
+    /// away, otherwise from the extension of `path`, falling back to raw
+    #[must_use]
+    pub fn detect(path: &Path, data: &[u8]) -> Self {
+        let guess = Self::guess(path);
+        let text = str::from_utf8(data).map(str::trim_start);
+        match text {
+            Ok(text) if text.starts_with("v2.0 raw") => Self::Logisim,
+            // Logisim images always have the header, anything else called
+            // .img is more likely to be raw
+            Ok(_) if guess != Self::Logisim && guess != Self::Raw => guess,
+            Ok(text) if text.starts_with(':') => Self::IntelHex,
+            Ok(text) if text.starts_with('@') => Self::Memh,
+            _ => Self::Raw,
+        }
+    }
+
+    /// Load `data` in this format into memory, moved up by `base` bytes
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` pointing at the first malformed line, or if
+    /// anything lands past the end of memory
+    pub fn read(self, data: &[u8], base: u8) -> Result<Image, Error> {
+        let mut image = Image::new();
+        let text = || {
+            str::from_utf8(data)
+                .map_err(|_| Error::general(format!("{:?} images must be text", self)))
+        };
+        match self {
+            Self::Raw => {
+                for (offset, byte) in data.iter().enumerate() {
+                    let addr = base as usize + offset;
+                    if addr >= MEM_SIZE {
+                        return Err(Error::general(format!(
+                            "{} bytes don't fit in memory from 0x{:02X}",
+                            data.len(),
+                            base
+                        )));
+                    }
+                    #[allow(clippy::cast_possible_truncation)]
+                    image.set(addr as u8, *byte);
+                }
+            }
+            Self::Logisim => read_logisim(&mut image, text()?, base)?,
+            Self::IntelHex => read_intel_hex(&mut image, text()?, base)?,
+            Self::Memh => read_memh(&mut image, text()?, base)?,
+            Self::Rust | Self::C => {
+                return Err(Error::general(format!(
+                    "{:?} arrays can be written but not loaded",
+                    self
+                )))
+            }
+        }
+        Ok(image)
+    }
+
+    /// `image` in this format
+    #[must_use]
+    pub fn write(self, image: &Image) -> Vec<u8> {
+        match self {
+            Self::Raw => leading(image).to_vec(),
+            Self::Logisim => logisim(image).into_bytes(),
+            Self::IntelHex => intel_hex(image).into_bytes(),
+            Self::Memh => memh(image).into_bytes(),
+            Self::Rust => array(image, "pub const IMAGE: [u8; {}] = [", "];").into_bytes(),
+            Self::C => array(image, "const unsigned char image[{}] = {", "};").into_bytes(),
+        }
+    }
+}
+
+/// Memory from address 0 up to the last used byte
+fn leading(image: &Image) -> &[u8] {
+    match image.extent() {
+        Some((_, last)) => &image[..=last as usize],
+        None => &[],
+    }
+}
+
+/// Runs of used bytes, split so none is longer than a line, with the
+/// address each starts at
+fn blocks(image: &Image) -> Vec<(u8, Vec<u8>)> {
+    let mut blocks: Vec<(u8, Vec<u8>)> = Vec::new();
+    for (addr, byte) in image.iter().enumerate() {
+        #[allow(clippy::cast_possible_truncation)]
+        let addr = addr as u8;
+        if !image.used(addr) {
+            continue;
+        }
+        match blocks.last_mut() {
+            Some((start, bytes))
+                if *start as usize + bytes.len() == addr as usize && bytes.len() < LINE =>
+            {
+                bytes.push(*byte);
+            }
+            _ => blocks.push((addr, vec![*byte])),
+        }
+    }
+    blocks
+}
+
+fn logisim(image: &Image) -> String {
+    let mut values = Vec::new();
+    let mut bytes = leading(image).iter().peekable();
+    while let Some(byte) = bytes.next() {
+        let mut count = 1;
+        while bytes.next_if_eq(&byte).is_some() {
+            count += 1;
+        }
+        if count >= RUN {
+            values.push(format!("{}*{:x}", count, byte));
+        } else {
+            values.extend((0..count).map(|_| format!("{:x}", byte)));
+        }
+    }
+    let mut out = String::from("v2.0 raw\n");
+    for line in values.chunks(LINE) {
+        out.push_str(&line.join(" "));
+        out.push('\n');
+    }
+    out
+}
+
+fn intel_hex(image: &Image) -> String {
+    let mut out = String::new();
+    for (start, bytes) in blocks(image) {
+        // Lines are at most 16 bytes, so the length fits
+        #[allow(clippy::cast_possible_truncation)]
+        let mut sum = (bytes.len() as u8).wrapping_add(start);
+        let _ = write!(out, ":{:02X}00{:02X}00", bytes.len(), start);
+        for byte in &bytes {
+            sum = sum.wrapping_add(*byte);
+            let _ = write!(out, "{:02X}", byte);
+        }
+        let _ = writeln!(out, "{:02X}", sum.wrapping_neg());
+    }
+    out.push_str(":00000001FF\n");
+    out
+}
+
+fn memh(image: &Image) -> String {
+    let mut out = String::new();
+    let mut next = None;
+    for (start, bytes) in blocks(image) {
+        if next != Some(start as usize) {
+            let _ = writeln!(out, "@{:02x}", start);
+        }
+        next = Some(start as usize + bytes.len());
+        let line: Vec<_> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let _ = writeln!(out, "{}", line.join(" "));
+    }
+    out
+}
+
+/// Source for an array of the leading bytes, `open` has `{}` where the
+/// length goes
+fn array(image: &Image, open: &str, close: &str) -> String {
+    let bytes = leading(image);
+    let mut out = open.replace("{}", &bytes.len().to_string());
+    out.push('\n');
+    for line in bytes.chunks(LINE / 2) {
+        let line: Vec<_> = line.iter().map(|byte| format!("0x{:02X},", byte)).collect();
+        let _ = writeln!(out, "    {}", line.join(" "));
+    }
+    out.push_str(close);
+    out.push('\n');
+    out
+}
+
+/// The whitespace separated words of `line` with the column each starts at
+fn words(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    line.split(char::is_whitespace)
+        .scan(0, |column, word| {
+            let start = *column;
+            *column += word.len() + 1;
+            Some((start, word))
+        })
+        .filter(|(_, word)| !word.is_empty())
+}
+
+/// Where `word` starts at `column` on line `num`
+fn at(num: usize, column: usize, word: &str) -> Range {
+    Range::new(
+        Point::new(num, column),
+        Point::new(num, column + word.len()),
+    )
+}
+
+/// Images are plain ASCII, anything else is a mistake (or not an image)
+fn ascii(num: usize, line: &str) -> Result<(), Error> {
+    match line.chars().enumerate().find(|(_, c)| !c.is_ascii()) {
+        Some((column, c)) => Err(Error::new(
+            format!("Unexpected '{}', images are plain ASCII", c),
+            at(num, column, "?"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Store `byte` at `addr`, which may be past the end of memory
+fn place(image: &mut Image, addr: usize, byte: u8, here: Range) -> Result<(), Error> {
+    if addr >= MEM_SIZE {
+        return Err(Error::new(
+            format!("0x{:X} is past the end of memory", addr),
+            here,
+        ));
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    image.set(addr as u8, byte);
+    Ok(())
+}
+
+fn read_logisim(image: &mut Image, text: &str, base: u8) -> Result<(), Error> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, header)) if header.trim() == "v2.0 raw" => (),
+        Some((_, header)) => {
+            return Err(Error::new(
+                format!("Expected 'v2.0 raw', got '{}'", header.trim()),
+                at(1, 0, header),
+            ))
+        }
+        None => return Err(Error::general("Empty Logisim image".to_string())),
+    }
+    let mut addr = base as usize;
+    for (idx, line) in lines {
+        let num = idx + 1;
+        ascii(num, line)?;
+        let line = line.split('#').next().unwrap_or("");
+        for (column, word) in words(line) {
+            let here = at(num, column, word);
+            let bad = || Error::new(format!("Expected a value or N*value, got '{}'", word), here);
+            let (count, value) = match word.split_once('*') {
+                Some((count, value)) => (count.parse().map_err(|_| bad())?, value),
+                None => (1, word),
+            };
+            let value = u8::from_str_radix(value, 16).map_err(|_| bad())?;
+            for _ in 0..count {
+                place(image, addr, value, here)?;
+                addr += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_intel_hex(image: &mut Image, text: &str, base: u8) -> Result<(), Error> {
+    for (idx, line) in text.lines().enumerate() {
+        let num = idx + 1;
+        ascii(num, line)?;
+        let record = line.trim();
+        if record.is_empty() {
+            continue;
+        }
+        let here = at(num, 0, line);
+        let err = |message: String| Error::new(message, here);
+        let hex = record.strip_prefix(':').ok_or_else(|| {
+            err(format!(
+                "Expected a record starting with ':', got '{}'",
+                record
+            ))
+        })?;
+        if hex.len() % 2 != 0 || hex.len() < 10 {
+            return Err(err("Record is too short or has half a byte".to_string()));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err(format!("Bad hex in '{}'", record)))?;
+        let sum = bytes.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+        if sum != 0 {
+            return Err(err(format!(
+                "Checksum should be {:02X}, not {:02X}",
+                bytes[bytes.len() - 1].wrapping_sub(sum),
+                bytes[bytes.len() - 1]
+            )));
+        }
+        let data = &bytes[4..bytes.len() - 1];
+        if data.len() != bytes[0] as usize {
+            return Err(err(format!(
+                "Record says it holds {} bytes but has {}",
+                bytes[0],
+                data.len()
+            )));
+        }
+        let addr = usize::from(bytes[1]) << 8 | usize::from(bytes[2]);
+        match bytes[3] {
+            0x00 => {
+                for (offset, byte) in data.iter().enumerate() {
+                    place(image, base as usize + addr + offset, *byte, here)?;
+                }
+            }
+            0x01 => return Ok(()),
+            // Segment and linear addresses, only zero makes sense here
+            0x02 | 0x04 if data.iter().all(|byte| *byte == 0) => (),
+            0x02 | 0x04 => return Err(err("Address is past the end of memory".to_string())),
+            // Start addresses, execution always starts at 0
+            0x03 | 0x05 => (),
+            kind => return Err(err(format!("Unknown record type {:02X}", kind))),
+        }
+    }
+    Err(Error::general(
+        "Intel HEX is missing its end of file record".to_string(),
+    ))
+}
+
+fn read_memh(image: &mut Image, text: &str, base: u8) -> Result<(), Error> {
+    let mut addr = base as usize;
+    for (idx, line) in text.lines().enumerate() {
+        let num = idx + 1;
+        ascii(num, line)?;
+        let line = line.split("//").next().unwrap_or("");
+        for (column, word) in words(line) {
+            let here = at(num, column, word);
+            if let Some(start) = word.strip_prefix('@') {
+                let start = usize::from_str_radix(start, 16)
+                    .map_err(|_| Error::new(format!("Bad address '{}'", word), here))?;
+                addr = base as usize + start;
+            } else {
+                let value = u8::from_str_radix(word, 16).map_err(|_| {
+                    Error::new(format!("Expected a hex byte, got '{}'", word), here)
+                })?;
+                place(image, addr, value, here)?;
+                addr += 1;
+            }
+        }
+    }
+    Ok(())
+}
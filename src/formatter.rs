@@ -0,0 +1,196 @@
+use crate::control::condition;
+use crate::parse::{CONDITIONALS, MNEMONICS};
+use crate::stream::{Error, Input};
+use crate::token::{Range, Token, Type};
+
+use std::collections::BTreeMap;
+
+/// Column mnemonics start in
+const MNEMONIC: usize = 8;
+
+/// Column operands start in
+const OPERANDS: usize = 16;
+
+/// Column trailing comments start in
+const COMMENT: usize = 40;
+
+/// Directives that structure the file, written against the left margin
+const OUTLINE: &[&str] = &[
+    "asect", "rsect", "tplate", "macro", "mend", "include", "end", ".if", ".elsif", ".else",
+    ".endif",
+];
+
+/// Lay out `source` in columns of labels, mnemonics, operands and comments,
+/// keeping every token so it assembles the same
+///
+/// # Errors
+///
+/// Will return `Err` if `source` can't be split into tokens
+pub fn reformat(source: &str) -> Result<String, Error> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut tokens: BTreeMap<usize, Vec<Token>> = BTreeMap::new();
+    let mut input = Input::from(source.to_string());
+    loop {
+        let token = input.consume()?;
+        match *token {
+            Type::Eof => break,
+            Type::Text(_) | Type::Character(_)
+                if token.range().start().line() != token.range().end().line() =>
+            {
+                return Err(Error::new(
+                    "Can't lay out text spanning several lines".to_string(),
+                    token.range(),
+                ));
+            }
+            _ => tokens
+                .entry(token.range().start().line())
+                .or_default()
+                .push(token),
+        }
+    }
+
+    let mut out = String::new();
+    let mut blank = false;
+    for (idx, line) in lines.iter().enumerate() {
+        match tokens.get(&(idx + 1)) {
+            Some(tokens) => {
+                if blank && !out.is_empty() {
+                    out.push('\n');
+                }
+                blank = false;
+                out.push_str(&layout(line, tokens));
+                out.push('\n');
+            }
+            None => blank = true,
+        }
+    }
+    Ok(out)
+}
+
+/// One line of tokens in columns
+fn layout(line: &str, tokens: &[Token]) -> String {
+    let (comment, tokens) = match tokens.split_last() {
+        Some((last, rest)) => match &**last {
+            Type::Comment(text) => (Some(format!("#{}", text.trim_end())), rest),
+            _ => (None, tokens),
+        },
+        None => (None, tokens),
+    };
+
+    // A comment on its own keeps to the margin or the mnemonic column
+    if tokens.is_empty() {
+        let comment = comment.unwrap_or_default();
+        return if line.starts_with(char::is_whitespace) {
+            format!("{:width$}{}", "", comment, width = MNEMONIC)
+        } else {
+            comment
+        };
+    }
+
+    let (label, rest) = match tokens {
+        [name, mark, rest @ ..] if is_label(name, mark) => {
+            (format!("{}{}", text(line, name), **mark), rest)
+        }
+        _ => (String::new(), tokens),
+    };
+
+    let mut out = label;
+    if let Some((first, operands)) = rest.split_first() {
+        let mnemonic = mnemonic(line, first);
+        let outline = out.is_empty() && OUTLINE.contains(&mnemonic.as_ref());
+        pad(&mut out, if outline { 0 } else { MNEMONIC });
+        out.push_str(&mnemonic);
+        if !operands.is_empty() {
+            pad(&mut out, if outline { 0 } else { OPERANDS });
+            out.push_str(&self::operands(line, &mnemonic, first.range(), operands));
+        }
+    }
+    if let Some(comment) = comment {
+        pad(&mut out, COMMENT);
+        out.push_str(&comment);
+    }
+    out
+}
+
+/// Does the line start with a label, `name:`, `name>` or `1:`
+fn is_label(name: &Token, mark: &Token) -> bool {
+    matches!(
+        (&**name, &**mark),
+        (Type::Symbol(_) | Type::Local(_), Type::Colon | Type::Gt)
+            | (Type::Decimal(_), Type::Colon)
+    )
+}
+
+/// Fill `out` with spaces up to `column`, or add one if it's already past
+fn pad(out: &mut String, column: usize) {
+    let len = out.chars().count();
+    if len < column {
+        out.push_str(&" ".repeat(column - len));
+    } else if !out.is_empty() {
+        out.push(' ');
+    }
+}
+
+fn mnemonic(line: &str, token: &Token) -> String {
+    match &**token {
+        Type::Symbol(name)
+            if MNEMONICS.contains(&name.to_lowercase().as_ref())
+                || CONDITIONALS.contains(&name.to_lowercase().as_ref()) =>
+        {
+            name.to_lowercase()
+        }
+        _ => text(line, token),
+    }
+}
+
+/// The operands of `mnemonic` with single spaces after commas and between
+/// words, joining anything that was written without spaces between it
+fn operands(line: &str, mnemonic: &str, mut last: Range, tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&Token> = None;
+    for token in tokens {
+        let spaced = last.end() != token.range().start();
+        match (previous.map(|p| &**p), &**token) {
+            (_, Type::Comma) | (None, _) => (),
+            (Some(Type::Comma), _) => out.push(' '),
+            _ if spaced => out.push(' '),
+            _ => (),
+        }
+        let written = match &**token {
+            Type::Symbol(cond)
+                if previous.is_none()
+                    && matches!(mnemonic, "is" | "stays" | "until")
+                    && condition(&cond.to_lowercase()).is_some() =>
+            {
+                cond.to_lowercase()
+            }
+            Type::Symbol(dup)
+                if matches!(mnemonic, "dc" | "asciz") && dup.eq_ignore_ascii_case("dup") =>
+            {
+                dup.to_lowercase()
+            }
+            _ => text(line, token),
+        };
+        out.push_str(&written);
+        previous = Some(token);
+        last = token.range();
+    }
+    out
+}
+
+/// How `token` is written, numbers in their standard form and anything else
+/// as it was in `line`
+fn text(line: &str, token: &Token) -> String {
+    match &**token {
+        Type::Decimal(num) => format!("{}", num),
+        Type::Hexadecimal(num) => format!("0x{:02X}", num),
+        Type::Binary(num) => format!("0b{:08b}", num),
+        Type::Octal(num) => format!("0o{:o}", num),
+        Type::Text(_) | Type::Character(_) => line
+            .chars()
+            .skip(token.range().start().column())
+            .take(token.range().end().column() - token.range().start().column())
+            .collect(),
+        other => other.to_string(),
+    }
+}
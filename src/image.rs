@@ -0,0 +1,58 @@
+use crate::machine::MEM_SIZE;
+
+use std::ops::Deref;
+
+/// The contents of memory produced by assembling a program
+pub struct Image {
+    memory: [u8; MEM_SIZE],
+    used: [bool; MEM_SIZE],
+}
+
+impl Default for Image {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Image {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            memory: [0; MEM_SIZE],
+            used: [false; MEM_SIZE],
+        }
+    }
+
+    pub fn set(&mut self, i: u8, v: u8) {
+        self.memory[i as usize] = v;
+        self.used[i as usize] = true;
+    }
+
+    #[must_use]
+    pub fn get(&self, i: u8) -> u8 {
+        self.memory[i as usize]
+    }
+
+    /// Has `i` been written to by the program
+    #[must_use]
+    pub fn used(&self, i: u8) -> bool {
+        self.used[i as usize]
+    }
+
+    /// The first and last addresses written to, `None` if nothing was
+    #[must_use]
+    pub fn extent(&self) -> Option<(u8, u8)> {
+        let first = self.used.iter().position(|used| *used)?;
+        let last = self.used.iter().rposition(|used| *used)?;
+        #[allow(clippy::cast_possible_truncation)]
+        Some((first as u8, last as u8))
+    }
+}
+
+impl Deref for Image {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.memory
+    }
+}
@@ -1,27 +1,53 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
 #![allow(clippy::non_ascii_literal)]
+#![allow(clippy::uninlined_format_args)]
 
-// mod assemble;
 mod alu;
+mod assemble;
+mod control;
+mod disassemble;
+mod expr;
+mod format;
+mod formatter;
+mod image;
+mod link;
+mod listing;
 mod machine;
+mod macros;
 mod node;
+mod object;
 mod opcodes;
 mod parse;
 mod section;
+mod source;
 mod stack;
 mod stream;
+mod symbols;
 mod token;
+mod warning;
 
 // Make enough public to easily run programs
-// pub use crate::assemble::Assemble;
+pub use crate::assemble::Assemble;
+pub use crate::disassemble::{Decoded, Disassembler};
+pub use crate::format::Format;
+pub use crate::formatter::reformat;
+pub use crate::image::Image;
+pub use crate::link::{Linked, Linker, Placed};
+pub use crate::listing::Listing;
 pub use crate::machine::ChangeEvent;
-pub use crate::machine::Observer;
-// pub use crate::parse::Parser;
 pub use crate::machine::Machine;
+pub use crate::machine::Observer;
 pub use crate::machine::{Response, COUNTER, SP, STATUS};
 pub use crate::node::{Node, Type as NodeType};
-pub use crate::parse::Parser;
+pub use crate::object::{
+    Object, Placement, Relocation, Segment, Span, Target, HEADER as OBJECT_HEADER,
+};
+pub use crate::parse::{Parser, MAX_ERRORS};
+pub use crate::section::Section;
+pub use crate::source::{FileId, Sources};
 pub use crate::stream::Error;
 pub use crate::stream::Input;
-pub use crate::token::{Token, Type};
+pub use crate::symbols::{Constant, Kind as SymbolKind, Location, Symbol, SymbolTable};
+pub use crate::token::{Point, Range, Token, Type};
+pub use crate::warning::{Allowed, Code as WarningCode, Warning};
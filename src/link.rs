@@ -0,0 +1,415 @@
+use crate::image::Image;
+use crate::machine::MEM_SIZE;
+use crate::object::{Object, Placement, Segment, Target};
+use crate::stream::Error;
+use crate::symbols::Location;
+use crate::token::Range;
+use crate::warning::{Code, Warning};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Where the interrupt vectors live, relocatable sections are kept out
+pub const VECTORS: usize = 0xF0;
+
+/// Where a section ended up in memory
+#[derive(Clone, Debug)]
+pub struct Placed {
+    /// The unit (usually file) the section came from
+    pub unit: String,
+    /// The rsect name, `None` for an asect
+    pub name: Option<String>,
+    /// Index of the segment within the unit's object
+    pub segment: usize,
+    pub start: u8,
+    pub size: usize,
+}
+
+impl fmt::Display for Placed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "rsect {} in {}", name, self.unit),
+            None => write!(f, "asect 0x{:02X} in {}", self.start, self.unit),
+        }
+    }
+}
+
+/// The output of a successful link
+pub struct Linked {
+    pub image: Image,
+    pub map: Vec<Placed>,
+    /// Relocatable sections that had to go in the interrupt vectors
+    pub warnings: Vec<Warning>,
+}
+
+impl Linked {
+    /// Where rsect `name` of `unit` was placed
+    #[must_use]
+    pub fn start(&self, unit: &str, name: &str) -> Option<u8> {
+        self.map
+            .iter()
+            .find(|placed| placed.unit == unit && placed.name.as_deref() == Some(name))
+            .map(|placed| placed.start)
+    }
+
+    /// The final address of `location` in `unit`
+    #[must_use]
+    pub fn address(&self, unit: &str, location: &Location) -> Option<u8> {
+        match location {
+            Location::Absolute(addr) => Some(*addr),
+            Location::Relative(sect, offset) => self
+                .start(unit, sect)
+                .map(|start| start.wrapping_add(*offset)),
+        }
+    }
+}
+
+impl fmt::Display for Linked {
+    /// The section map sorted by address, with the gaps between sections
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut owners = [None; MEM_SIZE];
+        for (idx, placed) in self.map.iter().enumerate() {
+            let start = placed.start as usize;
+            for owner in &mut owners[start..start + placed.size] {
+                *owner = Some(idx);
+            }
+        }
+
+        // Each run of addresses with the same owner, split where the
+        // vectors start
+        let mut rows: Vec<(usize, usize, Option<usize>)> = Vec::new();
+        for (addr, owner) in owners.iter().enumerate() {
+            match rows.last_mut() {
+                Some((_, end, last)) if *last == *owner && (owner.is_some() || addr != VECTORS) => {
+                    *end = addr;
+                }
+                _ => rows.push((addr, addr, *owner)),
+            }
+        }
+        // Sections can be empty, but should still be listed
+        for (idx, placed) in self.map.iter().enumerate() {
+            if placed.size == 0 {
+                rows.push((placed.start as usize, placed.start as usize, Some(idx)));
+            }
+        }
+        rows.sort_by_key(|(start, _, owner)| (*start, owner.is_some()));
+
+        writeln!(f, "start end  size section")?;
+        let (mut free, mut reserved) = (0, 0);
+        for (start, end, owner) in rows {
+            let size = end - start + 1;
+            match owner {
+                Some(idx) => {
+                    let placed = &self.map[idx];
+                    writeln!(
+                        f,
+                        "0x{:02X}  0x{:02X} {:4} {}",
+                        start,
+                        (start + placed.size).saturating_sub(1),
+                        placed.size,
+                        placed
+                    )?;
+                }
+                None if start >= VECTORS => {
+                    reserved += size;
+                    writeln!(
+                        f,
+                        "0x{:02X}  0x{:02X} {:4} (reserved for interrupt vectors)",
+                        start, end, size
+                    )?;
+                }
+                None => {
+                    free += size;
+                    writeln!(f, "0x{:02X}  0x{:02X} {:4} (free)", start, end, size)?;
+                }
+            }
+        }
+        writeln!(
+            f,
+            "{} of {} bytes used, {} free, {} reserved",
+            MEM_SIZE - free - reserved,
+            MEM_SIZE,
+            free,
+            reserved
+        )
+    }
+}
+
+/// An error at the declaration of `name` in `object`, if it's known
+fn declared(message: String, object: &Object, name: &str) -> Error {
+    match object.origins.get(name) {
+        Some(range) => Error::new(message, *range),
+        None => Error::general(message),
+    }
+}
+
+/// Start address of each segment, keyed by unit and segment index
+type Starts = HashMap<(usize, usize), u8>;
+
+/// Combines objects into a single memory image
+#[derive(Default)]
+pub struct Linker {
+    units: Vec<(String, Object)>,
+}
+
+impl Linker {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the object for `unit` to the link
+    pub fn add(&mut self, unit: String, object: Object) {
+        self.units.push((unit, object));
+    }
+
+    /// Where the source for the byte at `addr` in `placed` is, if it's known
+    fn origin(&self, placed: &Placed, addr: usize) -> Option<Range> {
+        let (_, object) = self.units.iter().find(|(unit, _)| *unit == placed.unit)?;
+        object.segments[placed.segment].origin(addr - placed.start as usize)
+    }
+
+    /// Claim `size` bytes from `start`, failing if anything already lives there
+    fn claim(
+        &self,
+        owners: &mut [Option<usize>],
+        map: &[Placed],
+        placed: Placed,
+    ) -> Result<Placed, Error> {
+        let start = placed.start as usize;
+        let end = start + placed.size;
+        if end > MEM_SIZE {
+            return Err(Error::general(format!(
+                "{} is {} bytes and runs past the end of memory (0x{:02X}-0x{:X})",
+                placed, placed.size, start, end
+            )));
+        }
+        if let Some(other) = owners[start..end].iter().find_map(|owner| *owner) {
+            let clash = (start..end)
+                .find(|i| owners[*i] == Some(other))
+                .unwrap_or(start);
+            let message = format!("{} overlaps {} at 0x{:02X}", placed, map[other], clash);
+            let err = match self.origin(&placed, clash) {
+                Some(range) => Error::new(message, range),
+                None => Error::general(message),
+            };
+            return Err(match self.origin(&map[other], clash) {
+                Some(range) => err.note(format!("0x{:02X} is already used here", clash), range),
+                None => err,
+            });
+        }
+        for owner in &mut owners[start..end] {
+            *owner = Some(map.len());
+        }
+        Ok(placed)
+    }
+
+    /// Pick a start address for every section
+    ///
+    /// Absolute sections go where they ask to be, relocatable sections are
+    /// put in the first gap large enough to hold them
+    fn place(&self) -> Result<(Vec<Placed>, Starts, Vec<Warning>), Error> {
+        let mut owners = [None; MEM_SIZE];
+        let mut map = Vec::new();
+        let mut warnings = Vec::new();
+        // Start address of each (unit, segment)
+        let mut starts = HashMap::new();
+
+        // Absolute sections are fixed so claim their space first
+        for (idx, (unit, object)) in self.units.iter().enumerate() {
+            for (seg, segment) in object.segments.iter().enumerate() {
+                if let Placement::Absolute(start) = segment.placement {
+                    let placed = self.claim(
+                        &mut owners,
+                        &map,
+                        Placed {
+                            unit: unit.clone(),
+                            name: None,
+                            segment: seg,
+                            start,
+                            size: segment.bytes.len(),
+                        },
+                    )?;
+                    map.push(placed);
+                    starts.insert((idx, seg), start);
+                }
+            }
+        }
+
+        // Then fit relocatable sections into the gaps
+        for (idx, (unit, object)) in self.units.iter().enumerate() {
+            for (seg, segment) in object.segments.iter().enumerate() {
+                if let Placement::Relocatable(name) = &segment.placement {
+                    let size = segment.bytes.len();
+                    if size > MEM_SIZE {
+                        let message = format!(
+                            "rsect {} in {} is {} bytes, more than the {} in memory",
+                            name, unit, size, MEM_SIZE
+                        );
+                        return Err(match segment.origin(MEM_SIZE) {
+                            Some(range) => Error::new(message, range),
+                            None => Error::general(message),
+                        });
+                    }
+                    let fits =
+                        |start: &usize| owners[*start..*start + size].iter().all(Option::is_none);
+                    // Keep clear of the interrupt vectors unless there's no
+                    // other room
+                    let start = (0..=VECTORS.saturating_sub(size))
+                        .find(fits)
+                        .or_else(|| (0..=MEM_SIZE.saturating_sub(size)).find(fits))
+                        .ok_or_else(|| {
+                            Error::general(format!(
+                                "No room in memory for rsect {} in {} ({} bytes, {} free)",
+                                name,
+                                unit,
+                                size,
+                                owners.iter().filter(|owner| owner.is_none()).count()
+                            ))
+                        })?;
+                    #[allow(clippy::cast_possible_truncation)]
+                    let start = start as u8;
+                    let placed = self.claim(
+                        &mut owners,
+                        &map,
+                        Placed {
+                            unit: unit.clone(),
+                            name: Some(name.clone()),
+                            segment: seg,
+                            start,
+                            size,
+                        },
+                    )?;
+                    if start as usize + size > VECTORS {
+                        warnings.push(self.vectors(&placed));
+                    }
+                    map.push(placed);
+                    starts.insert((idx, seg), start);
+                }
+            }
+        }
+
+        Ok((map, starts, warnings))
+    }
+
+    /// Warn that `placed` had to go in the interrupt vectors
+    fn vectors(&self, placed: &Placed) -> Warning {
+        let message = format!(
+            "{} is placed in the interrupt vectors (0xF0-0xFF) as there's no room below",
+            placed
+        );
+        match self.origin(placed, VECTORS.max(placed.start as usize)) {
+            Some(at) => Warning::new(Code::InterruptVector, message, at),
+            None => Warning::general(Code::InterruptVector, message),
+        }
+    }
+
+    /// Place every section, resolve symbols between units and patch
+    /// relocations
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if sections overlap, don't fit in memory, a symbol
+    /// is exported twice or an external is never provided
+    pub fn link(&self) -> Result<Linked, Error> {
+        let (map, starts, warnings) = self.place()?;
+
+        let section_start = |idx: usize, object: &Object, name: &str| {
+            object
+                .segments
+                .iter()
+                .position(|segment| segment.name() == Some(name))
+                .and_then(|seg| starts.get(&(idx, seg)).copied())
+        };
+
+        // Every exported symbol by name, remembering who exported it
+        let mut globals: HashMap<&str, (u8, usize)> = HashMap::new();
+        for (idx, (unit, object)) in self.units.iter().enumerate() {
+            for (name, location) in &object.entries {
+                let addr = match location {
+                    Location::Absolute(addr) => *addr,
+                    Location::Relative(sect, offset) => section_start(idx, object, sect)
+                        .ok_or_else(|| {
+                            Error::general(format!(
+                                "{} in {} refers to unknown rsect {}",
+                                name, unit, sect
+                            ))
+                        })?
+                        .wrapping_add(*offset),
+                };
+                if let Some((_, first)) = globals.insert(name, (addr, idx)) {
+                    let (first_unit, first_object) = &self.units[first];
+                    let err = declared(
+                        format!("{} is exported by both {} and {}", name, first_unit, unit),
+                        object,
+                        name,
+                    );
+                    return Err(match first_object.origins.get(name) {
+                        Some(range) => err.note(
+                            format!("{} was first exported by {} here", name, first_unit),
+                            *range,
+                        ),
+                        None => err,
+                    });
+                }
+            }
+        }
+
+        for (unit, object) in &self.units {
+            for name in &object.externals {
+                if !globals.contains_key(name.as_str()) {
+                    return Err(declared(
+                        format!(
+                            "{} is declared ext in {} but no unit exports it",
+                            name, unit
+                        ),
+                        object,
+                        name,
+                    ));
+                }
+            }
+        }
+
+        let mut image = Image::new();
+        for (idx, (unit, object)) in self.units.iter().enumerate() {
+            for (seg, segment) in object.segments.iter().enumerate() {
+                let start = starts[&(idx, seg)];
+                let bytes = Self::relocate(segment, |target| match target {
+                    Target::Section(name) => section_start(idx, object, name).ok_or_else(|| {
+                        Error::general(format!("Unknown rsect {} in {}", name, unit))
+                    }),
+                    Target::External(name) => globals
+                        .get(name.as_str())
+                        .map(|(addr, _)| *addr)
+                        .ok_or_else(|| {
+                            Error::general(format!("Unresolved external {} used in {}", name, unit))
+                        }),
+                })?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    #[allow(clippy::cast_possible_truncation)]
+                    image.set(start.wrapping_add(i as u8), *byte);
+                }
+            }
+        }
+
+        Ok(Linked {
+            image,
+            map,
+            warnings,
+        })
+    }
+
+    /// The bytes of `segment` with every relocation applied
+    fn relocate(
+        segment: &Segment,
+        address: impl Fn(&Target) -> Result<u8, Error>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = segment.bytes.clone();
+        for reloc in &segment.relocations {
+            let add = address(&reloc.target)?;
+            let byte = &mut bytes[reloc.offset as usize];
+            *byte = byte.wrapping_add(add);
+        }
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,169 @@
+use crate::link::Linked;
+use crate::object::{Object, Placement, Segment};
+use crate::source::Sources;
+use crate::symbols::SymbolTable;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Bytes shown on each row, longer data continues on the rows below
+const ROW: usize = 4;
+
+/// What one source line assembled to
+struct Row {
+    segment: usize,
+    address: u8,
+    bytes: Vec<u8>,
+}
+
+/// A listing of one unit after it has been linked
+pub struct Listing<'a> {
+    unit: &'a str,
+    sources: &'a Sources,
+    object: &'a Object,
+    symbols: &'a SymbolTable,
+    linked: &'a Linked,
+}
+
+impl<'a> Listing<'a> {
+    #[must_use]
+    pub fn new(
+        unit: &'a str,
+        sources: &'a Sources,
+        object: &'a Object,
+        symbols: &'a SymbolTable,
+        linked: &'a Linked,
+    ) -> Self {
+        Self {
+            unit,
+            sources,
+            object,
+            symbols,
+            linked,
+        }
+    }
+
+    /// Where `segment` was placed
+    fn start(&self, segment: &Segment) -> Option<u8> {
+        match &segment.placement {
+            Placement::Absolute(pos) => Some(*pos),
+            Placement::Relocatable(name) => self.linked.start(self.unit, name),
+        }
+    }
+
+    /// What each line of the main file (by number) assembled to, with
+    /// anything from an included file shown against its `include`
+    fn rows(&self) -> BTreeMap<usize, Row> {
+        let mut rows = BTreeMap::new();
+        for (idx, segment) in self.object.segments.iter().enumerate() {
+            let Some(start) = self.start(segment) else {
+                continue;
+            };
+            for span in &segment.spans {
+                let bytes = (span.offset..span.offset + span.size).map(|offset| {
+                    #[allow(clippy::cast_possible_truncation)]
+                    let address = start.wrapping_add(offset as u8);
+                    self.linked.image.get(address)
+                });
+                #[allow(clippy::cast_possible_truncation)]
+                let address = start.wrapping_add(span.offset as u8);
+                let range = self.sources.outermost(span.range);
+                rows.entry(range.start().line())
+                    .or_insert_with(|| Row {
+                        segment: idx,
+                        address,
+                        bytes: Vec::new(),
+                    })
+                    .bytes
+                    .extend(bytes);
+            }
+        }
+        rows
+    }
+
+    /// The marker shown when a new section starts
+    fn boundary(&self, f: &mut fmt::Formatter<'_>, segment: &Segment) -> fmt::Result {
+        let start = self.start(segment).unwrap_or(0);
+        let end = (start as usize + segment.bytes.len()).saturating_sub(1);
+        match &segment.placement {
+            Placement::Absolute(_) => write!(f, "---- asect 0x{:02X}", start)?,
+            Placement::Relocatable(name) => write!(f, "---- rsect {}", name)?,
+        }
+        writeln!(
+            f,
+            ": 0x{:02X}-0x{:02X} ({} bytes) ----",
+            start,
+            end,
+            segment.bytes.len()
+        )
+    }
+}
+
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Listing of {}", self.unit)?;
+        writeln!(f)?;
+        writeln!(f, "addr  bytes        line  source")?;
+
+        let rows = self.rows();
+        let mut segment = None;
+        let source = self.sources.text(0).unwrap_or_default();
+        for (idx, line) in source.lines().enumerate() {
+            let num = idx + 1;
+            let Some(row) = rows.get(&num) else {
+                writeln!(f, "{:18}{:>5}  {}", "", num, line)?;
+                continue;
+            };
+            if segment != Some(row.segment) {
+                segment = Some(row.segment);
+                self.boundary(f, &self.object.segments[row.segment])?;
+            }
+            let mut chunks = row.bytes.chunks(ROW);
+            let first = chunks.next().unwrap_or(&[]);
+            writeln!(
+                f,
+                "0x{:02X}  {:12}{:>5}  {}",
+                row.address,
+                hex(first),
+                num,
+                line
+            )?;
+            for (n, chunk) in chunks.enumerate() {
+                #[allow(clippy::cast_possible_truncation)]
+                let address = row.address.wrapping_add(((n + 1) * ROW) as u8);
+                writeln!(f, "0x{:02X}  {}", address, hex(chunk))?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Symbols")?;
+        for (name, symbol) in self.symbols.sorted() {
+            match self.linked.address(self.unit, symbol.location()) {
+                Some(addr) => writeln!(f, "{:16} 0x{:02X}", name, addr)?,
+                None => writeln!(f, "{:16} {}", name, symbol.location())?,
+            }
+        }
+        for (name, offset) in self.symbols.fields() {
+            writeln!(f, "{:16} field {}", name, offset)?;
+        }
+        for (name, constant) in self.symbols.constants() {
+            writeln!(f, "{:16} {}", name, constant)?;
+        }
+        for name in self.symbols.externals() {
+            writeln!(f, "{:16} ext", name)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Memory")?;
+        write!(f, "{}", self.linked)
+    }
+}
+
+/// `bytes` as space separated hex
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
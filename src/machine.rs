@@ -1,4 +1,4 @@
-use crate::alu::ALU;
+use crate::alu::Alu;
 use crate::op1;
 use crate::op2;
 use crate::opcodes::{
@@ -80,6 +80,14 @@ impl Machine {
         Self::emit(&ChangeEvent { idx: i, val: v }, &self.mem_listeners);
     }
 
+    /// Copy `bytes` into memory starting from address 0
+    pub fn load(&mut self, bytes: &[u8]) {
+        for (i, v) in bytes.iter().take(MEM_SIZE).enumerate() {
+            #[allow(clippy::cast_possible_truncation)]
+            self.set_mem(i as u8, *v);
+        }
+    }
+
     #[must_use]
     pub fn mem(&self, i: u8) -> u8 {
         self.memory[i as usize]
@@ -208,7 +216,7 @@ impl Machine {
                             // Yikes now we need the rand crate
                         }
                         OP_IOI | OP_RTI | OP_OSIX => {
-                            self.handle_interrupt(instruction, interrupt)?
+                            self.handle_interrupt(instruction, interrupt)?;
                         }
                         _ => return Err(Response::UnknownInstruction),
                     }
@@ -331,9 +339,9 @@ impl Machine {
     }
 
     #[must_use]
-    pub fn iter_mem(&self) -> MemIter {
+    pub fn iter_mem(&self) -> MemIter<'_> {
         MemIter {
-            machine: Box::new(self),
+            machine: self,
             pos: 0,
             done: false,
         }
@@ -341,12 +349,12 @@ impl Machine {
 }
 
 pub struct MemIter<'a> {
-    machine: Box<&'a Machine>,
+    machine: &'a Machine,
     pos: u8,
     done: bool,
 }
 
-impl<'a> Iterator for MemIter<'a> {
+impl Iterator for MemIter<'_> {
     type Item = (u8, u8);
 
     fn next(&mut self) -> Option<Self::Item> {
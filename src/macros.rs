@@ -0,0 +1,89 @@
+use crate::stream::Error;
+use crate::token::{Range, Token, Type};
+
+use std::collections::VecDeque;
+
+/// How many expansions can be in progress at once before we assume a macro
+/// is calling itself forever
+pub const MAX_DEPTH: usize = 32;
+
+/// A `macro name/N ... mend` definition
+#[derive(Clone, Debug)]
+pub struct Macro {
+    params: usize,
+    body: Vec<Token>,
+    range: Range,
+}
+
+impl Macro {
+    /// # Errors
+    ///
+    /// Will return `Err` if the body uses a parameter outside `$1..$params`
+    pub fn new(name: &str, params: usize, body: Vec<Token>, range: Range) -> Result<Self, Error> {
+        for token in &body {
+            if let Type::Parameter(n) = **token {
+                if n == 0 || n as usize > params {
+                    return Err(Error::new(
+                        format!("{} takes {} parameters, there is no ${}", name, params, n),
+                        token.range(),
+                    )
+                    .note(format!("{} is defined here", name), range));
+                }
+            }
+        }
+        Ok(Self {
+            params,
+            body,
+            range,
+        })
+    }
+
+    /// Number of arguments an invocation must supply
+    #[must_use]
+    pub fn params(&self) -> usize {
+        self.params
+    }
+
+    /// Where the macro was defined
+    #[must_use]
+    pub fn range(&self) -> Range {
+        self.range
+    }
+
+    /// The body with `$n` replaced by the arguments and `$label` made
+    /// unique to expansion number `id`
+    ///
+    /// Arguments take the range of the `$n` they replace so errors point
+    /// into the body
+    #[must_use]
+    pub fn expand(&self, args: &[Vec<Token>], id: usize) -> VecDeque<Token> {
+        let mut tokens = VecDeque::with_capacity(self.body.len());
+        for token in &self.body {
+            match &**token {
+                Type::Parameter(n) => tokens.extend(
+                    args[*n as usize - 1]
+                        .iter()
+                        .map(|arg| Token::new((**arg).clone(), token.range())),
+                ),
+                Type::Local(name) => tokens.push_back(Token::new(
+                    Type::Symbol(format!("{}${}", name, id)),
+                    token.range(),
+                )),
+                _ => tokens.push_back(token.clone()),
+            }
+        }
+        tokens
+    }
+}
+
+/// An invocation currently being parsed
+pub struct Expansion {
+    pub name: String,
+    /// Where the macro was invoked
+    pub call: Range,
+    /// How many invocations led to this one, including itself and any
+    /// that have already been read to the end
+    pub depth: usize,
+    /// What is left of the expanded body
+    pub tokens: VecDeque<Token>,
+}
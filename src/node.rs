@@ -29,42 +29,52 @@ pub enum Type {
     Ld(Register, Register),
     Push(Register),
     Pop(Register),
-    Ldsa(Register, Literal),
-    Addsp(Literal),
-    Setsp(Literal),
+    Ldsa(Register, Box<Node>),
+    Addsp(Box<Node>),
+    Setsp(Box<Node>),
     Pushall,
     Popall,
     Ldi(Register, Box<Node>),
     Halt,
     Wait,
-    Jsr(Label),
+    Jsr(Box<Node>),
     Rts,
     Ioi,
     Rti,
     Crc,
-    Osix,
+    Osix(Literal),
     Rand,
-    BeqBz(Label),
-    BneBnz(Label),
-    BhsBcs(Label),
-    BloBcc(Label),
-    Bmi(Label),
-    Bpl(Label),
-    Bvs(Label),
-    Bvc(Label),
-    Bhi(Label),
-    Bls(Label),
-    Bge(Label),
-    Blt(Label),
-    Bgt(Label),
-    Ble(Label),
-    Br(Label),
-    Nop(Label),
+    BeqBz(Box<Node>),
+    BneBnz(Box<Node>),
+    BhsBcs(Box<Node>),
+    BloBcc(Box<Node>),
+    Bmi(Box<Node>),
+    Bpl(Box<Node>),
+    Bvs(Box<Node>),
+    Bvc(Box<Node>),
+    Bhi(Box<Node>),
+    Bls(Box<Node>),
+    Bge(Box<Node>),
+    Blt(Box<Node>),
+    Bgt(Box<Node>),
+    Ble(Box<Node>),
+    Br(Box<Node>),
+    Nop(Box<Node>),
     Ldc(Register, Register),
     Label(String),
     Entry(String),
     Signed(i8),
     Unsigned(u8),
+    /// A `set` or `equ` constant and the value it had when used
+    Constant(String, i32),
+    /// `.`, the address of the current instruction
+    Here,
+    /// `-a`
+    Negate(Box<Node>),
+    /// `a+b`
+    Sum(Box<Node>, Box<Node>),
+    /// `a-b`
+    Difference(Box<Node>, Box<Node>),
     Asect(Literal),
     Dc(Vec<Node>),
     Ds(u8),
@@ -75,18 +85,89 @@ pub enum Type {
 pub struct Node {
     data: Type,
     range: Range,
+    /// The macro invocation this came from
+    site: Option<Range>,
+    /// Made by lowering structured code rather than written
+    lowered: bool,
 }
 
 impl Node {
     #[must_use]
     pub fn new(data: Type, range: Range) -> Self {
-        Self { data, range }
+        Self {
+            data,
+            range,
+            site: None,
+            lowered: false,
+        }
+    }
+
+    /// Mark this node as produced by the macro invoked at `site`
+    #[must_use]
+    pub fn expanded_at(mut self, site: Range) -> Self {
+        self.site = Some(site);
+        self
+    }
+
+    /// Mark this node as made by lowering structured code
+    #[must_use]
+    pub fn lowered(mut self) -> Self {
+        self.lowered = true;
+        self
+    }
+
+    /// Was this made by the assembler, from a macro or structured code,
+    /// rather than written as it is
+    #[must_use]
+    pub fn generated(&self) -> bool {
+        self.lowered || self.site.is_some()
     }
 
     #[must_use]
     pub fn range(&self) -> Range {
         self.range
     }
+
+    /// Where the node appears in the source as written, the invocation
+    /// for anything produced by a macro
+    #[must_use]
+    pub fn site(&self) -> Range {
+        self.site.unwrap_or(self.range)
+    }
+}
+
+impl Type {
+    /// The expressions this holds, such as the target of a branch
+    #[must_use]
+    pub fn operands(&self) -> Vec<&Node> {
+        match self {
+            Self::Ldsa(_, a)
+            | Self::Addsp(a)
+            | Self::Setsp(a)
+            | Self::Ldi(_, a)
+            | Self::Jsr(a)
+            | Self::BeqBz(a)
+            | Self::BneBnz(a)
+            | Self::BhsBcs(a)
+            | Self::BloBcc(a)
+            | Self::Bmi(a)
+            | Self::Bpl(a)
+            | Self::Bvs(a)
+            | Self::Bvc(a)
+            | Self::Bhi(a)
+            | Self::Bls(a)
+            | Self::Bge(a)
+            | Self::Blt(a)
+            | Self::Bgt(a)
+            | Self::Ble(a)
+            | Self::Br(a)
+            | Self::Nop(a)
+            | Self::Negate(a) => vec![a],
+            Self::Sum(a, b) | Self::Difference(a, b) => vec![a, b],
+            Self::Dc(items) => items.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
 }
 
 impl Deref for Node {
@@ -120,41 +201,46 @@ impl fmt::Display for Type {
             Self::Ld(a, b) => write!(f, "ld r{}, r{}", a, b),
             Self::Push(a) => write!(f, "push r{}", a),
             Self::Pop(a) => write!(f, "pop r{}", a),
-            Self::Ldsa(r, o) => write!(f, "ldsa r{}, {}", r, o),
-            Self::Addsp(o) => write!(f, "addsp {}", o),
-            Self::Setsp(o) => write!(f, "setsp {}", o),
+            Self::Ldsa(r, o) => write!(f, "ldsa r{}, {}", r, o.data),
+            Self::Addsp(o) => write!(f, "addsp {}", o.data),
+            Self::Setsp(o) => write!(f, "setsp {}", o.data),
             Self::Pushall => write!(f, "pushall"),
             Self::Popall => write!(f, "popall"),
             Self::Ldi(reg, i) => write!(f, "ldi r{}, {}", reg, i.data),
             Self::Halt => write!(f, "halt"),
             Self::Wait => write!(f, "wait"),
-            Self::Jsr(l) => write!(f, "jsr {}", l),
+            Self::Jsr(l) => write!(f, "jsr {}", l.data),
             Self::Rts => write!(f, "rts"),
             Self::Ioi => write!(f, "ioi"),
             Self::Rti => write!(f, "rti"),
             Self::Crc => write!(f, "crc"),
-            Self::Osix => write!(f, "osix"),
+            Self::Osix(ps) => write!(f, "osix {}", ps),
             Self::Rand => write!(f, "rand"),
-            Self::BeqBz(l) => write!(f, "beq {}", l),
-            Self::BneBnz(l) => write!(f, "bne {}", l),
-            Self::BhsBcs(l) => write!(f, "bhs {}", l),
-            Self::BloBcc(l) => write!(f, "blo {}", l),
-            Self::Bmi(l) => write!(f, "bmi {}", l),
-            Self::Bpl(l) => write!(f, "pls {}", l),
-            Self::Bvs(l) => write!(f, "bvs {}", l),
-            Self::Bvc(l) => write!(f, "bvc {}", l),
-            Self::Bhi(l) => write!(f, "bhi {}", l),
-            Self::Bls(l) => write!(f, "bls {}", l),
-            Self::Bge(l) => write!(f, "bge {}", l),
-            Self::Blt(l) => write!(f, "blt {}", l),
-            Self::Bgt(l) => write!(f, "bgt {}", l),
-            Self::Ble(l) => write!(f, "ble {}", l),
-            Self::Br(l) => write!(f, "br {}", l),
-            Self::Nop(l) => write!(f, "nop {}", l),
+            Self::BeqBz(l) => write!(f, "beq {}", l.data),
+            Self::BneBnz(l) => write!(f, "bne {}", l.data),
+            Self::BhsBcs(l) => write!(f, "bhs {}", l.data),
+            Self::BloBcc(l) => write!(f, "blo {}", l.data),
+            Self::Bmi(l) => write!(f, "bmi {}", l.data),
+            Self::Bpl(l) => write!(f, "bpl {}", l.data),
+            Self::Bvs(l) => write!(f, "bvs {}", l.data),
+            Self::Bvc(l) => write!(f, "bvc {}", l.data),
+            Self::Bhi(l) => write!(f, "bhi {}", l.data),
+            Self::Bls(l) => write!(f, "bls {}", l.data),
+            Self::Bge(l) => write!(f, "bge {}", l.data),
+            Self::Blt(l) => write!(f, "blt {}", l.data),
+            Self::Bgt(l) => write!(f, "bgt {}", l.data),
+            Self::Ble(l) => write!(f, "ble {}", l.data),
+            Self::Br(l) => write!(f, "br {}", l.data),
+            Self::Nop(l) => write!(f, "nop {}", l.data),
             Self::Ldc(a, b) => write!(f, "ldc r{}, r{}", a, b),
             Self::Label(l) | Self::Entry(l) => write!(f, "{}", l),
             Self::Signed(num) => write!(f, "{}", num),
             Self::Unsigned(num) => write!(f, "{}", num),
+            Self::Constant(name, _) => write!(f, "{}", name),
+            Self::Here => write!(f, "."),
+            Self::Negate(a) => write!(f, "-{}", Grouped(a)),
+            Self::Sum(a, b) => write!(f, "{}+{}", a.data, Grouped(b)),
+            Self::Difference(a, b) => write!(f, "{}-{}", a.data, Grouped(b)),
             Self::Asect(p) => write!(f, "asect {}", p),
             Self::Dc(d) => write!(
                 f,
@@ -169,3 +255,15 @@ impl fmt::Display for Type {
         }
     }
 }
+
+/// Displays an operand in brackets if it's a sum or difference
+struct Grouped<'a>(&'a Node);
+
+impl fmt::Display for Grouped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.data {
+            Type::Sum(..) | Type::Difference(..) => write!(f, "({})", self.0.data),
+            _ => write!(f, "{}", self.0.data),
+        }
+    }
+}
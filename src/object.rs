@@ -0,0 +1,276 @@
+//! Relocatable object files
+//!
+//! An object holds one assembled source file before it has been placed in
+//! memory. It is stored as plain text, one record per line, so it can be
+//! inspected and diffed by hand:
+//!
+//! ```text
+//! belgium object 1
+//! # comments and blank lines are ignored
+//! asect 0x00            # start an absolute section at an address
+//! rsect main            # start a relocatable section
+//! data D0 00 B1         # bytes appended to the current section
+//! rel 1 main            # add the start of rsect main to byte 1 of this section
+//! xref 3 print          # add the address of the external print to byte 3
+//! entry start 0x00      # export an absolute symbol
+//! entry loop main 0x04  # export a symbol relative to an rsect
+//! ext print             # import a symbol from another file
+//! ```
+//!
+//! `rel` and `xref` offsets count from the start of the current section, the
+//! byte already holds the addend (usually the offset of a label within its
+//! section) so relocating is always an addition.
+
+use crate::machine::MEM_SIZE;
+use crate::source::FileId;
+use crate::stream::Error;
+use crate::symbols::Location;
+use crate::token::{Point, Range};
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// The magic first line of every object file
+pub const HEADER: &str = "belgium object 1";
+
+/// What a relocated byte has added to it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// The start address of a relocatable section in the same object
+    Section(String),
+    /// The address of a symbol imported from another object
+    External(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Relocation {
+    pub offset: u8,
+    pub target: Target,
+}
+
+/// Where a segment wants to be loaded
+#[derive(Clone, Debug, PartialEq)]
+pub enum Placement {
+    Absolute(u8),
+    Relocatable(String),
+}
+
+/// The bytes a piece of source produced
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub range: Range,
+    /// Offset into the segment
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// The bytes of one section
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub placement: Placement,
+    pub bytes: Vec<u8>,
+    pub relocations: Vec<Relocation>,
+    /// What each node emitted, only known when the object was assembled
+    /// rather than read from a file
+    pub spans: Vec<Span>,
+}
+
+impl Segment {
+    #[must_use]
+    pub fn new(placement: Placement) -> Self {
+        Self {
+            placement,
+            bytes: Vec::new(),
+            relocations: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// The rsect name, if this is relocatable
+    #[must_use]
+    pub fn name(&self) -> Option<&str> {
+        match &self.placement {
+            Placement::Absolute(_) => None,
+            Placement::Relocatable(name) => Some(name),
+        }
+    }
+
+    /// Where the source for the byte at `offset` is, if it's known
+    #[must_use]
+    pub fn origin(&self, offset: usize) -> Option<Range> {
+        self.spans
+            .iter()
+            .find(|span| span.offset <= offset && offset < span.offset + span.size)
+            .map(|span| span.range)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Object {
+    pub segments: Vec<Segment>,
+    /// Symbols this object makes available to others
+    pub entries: Vec<(String, Location)>,
+    /// Symbols this object expects another to provide
+    pub externals: Vec<String>,
+    /// Where each entry and external was declared, only known when the
+    /// object was assembled rather than read from a file
+    pub origins: HashMap<String, Range>,
+}
+
+impl Object {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move every range on by `base` files, after its sources have been
+    /// appended to others
+    pub fn renumber(&mut self, base: FileId) {
+        for segment in &mut self.segments {
+            for span in &mut segment.spans {
+                span.range = span.range.in_file(span.range.file() + base);
+            }
+        }
+        for range in self.origins.values_mut() {
+            *range = range.in_file(range.file() + base);
+        }
+    }
+
+    /// Read an object back from its text form
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with the line number of the first malformed record
+    pub fn read(text: &str) -> Result<Self, Error> {
+        let mut object = Self::new();
+        let mut lines = text.lines().enumerate();
+
+        match lines.next() {
+            Some((_, header)) if header.trim() == HEADER => (),
+            Some((_, header)) => {
+                return Err(Error::new(
+                    format!("Expected '{}', got '{}'", HEADER, header.trim()),
+                    line_range(1, header),
+                ))
+            }
+            None => {
+                return Err(Error::new(
+                    "Empty object file".to_string(),
+                    line_range(1, ""),
+                ))
+            }
+        }
+
+        for (idx, line) in lines {
+            let num = idx + 1;
+            let err = |message: String| Error::new(message, line_range(num, line));
+            let record = line.split('#').next().unwrap_or("");
+            let mut fields = record.split_whitespace();
+            let Some(kind) = fields.next() else {
+                continue;
+            };
+            let args: Vec<&str> = fields.collect();
+            let current = object.segments.last_mut();
+            match (kind, args.as_slice()) {
+                ("asect", [addr]) => object
+                    .segments
+                    .push(Segment::new(Placement::Absolute(byte(addr).map_err(err)?))),
+                ("rsect", [name]) => object
+                    .segments
+                    .push(Segment::new(Placement::Relocatable((*name).to_string()))),
+                ("data", bytes) => {
+                    let sect = current.ok_or_else(|| err("data outside a section".to_string()))?;
+                    for b in bytes {
+                        let b = u8::from_str_radix(b, 16)
+                            .map_err(|_| err(format!("Bad data byte '{}'", b)))?;
+                        sect.bytes.push(b);
+                    }
+                    if sect.bytes.len() > MEM_SIZE {
+                        return Err(err(format!(
+                            "Section is {} bytes, more than the {} in memory",
+                            sect.bytes.len(),
+                            MEM_SIZE
+                        )));
+                    }
+                }
+                ("rel" | "xref", [offset, name]) => {
+                    let sect = current.ok_or_else(|| err(format!("{} outside a section", kind)))?;
+                    let offset = byte(offset).map_err(err)?;
+                    if offset as usize >= sect.bytes.len() {
+                        return Err(err(format!("Relocation at {} is past the data", offset)));
+                    }
+                    let name = (*name).to_string();
+                    sect.relocations.push(Relocation {
+                        offset,
+                        target: if kind == "rel" {
+                            Target::Section(name)
+                        } else {
+                            Target::External(name)
+                        },
+                    });
+                }
+                ("entry", [name, addr]) => object.entries.push((
+                    (*name).to_string(),
+                    Location::Absolute(byte(addr).map_err(err)?),
+                )),
+                ("entry", [name, sect, offset]) => object.entries.push((
+                    (*name).to_string(),
+                    Location::Relative((*sect).to_string(), byte(offset).map_err(err)?),
+                )),
+                ("ext", [name]) => object.externals.push((*name).to_string()),
+                _ => return Err(err(format!("Unexpected record '{}'", record.trim()))),
+            }
+        }
+
+        Ok(object)
+    }
+}
+
+/// Parse a decimal or `0x` prefixed byte
+fn byte(text: &str) -> Result<u8, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("Expected a byte, got '{}'", text))
+}
+
+/// A range covering all of line `num`
+fn line_range(num: usize, line: &str) -> Range {
+    Range::new(Point::new(num, 0), Point::new(num, line.len()))
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for segment in &self.segments {
+            match &segment.placement {
+                Placement::Absolute(addr) => writeln!(f, "asect 0x{:02X}", addr)?,
+                Placement::Relocatable(name) => writeln!(f, "rsect {}", name)?,
+            }
+            for row in segment.bytes.chunks(16) {
+                let row: Vec<_> = row.iter().map(|b| format!("{:02X}", b)).collect();
+                writeln!(f, "data {}", row.join(" "))?;
+            }
+            for reloc in &segment.relocations {
+                match &reloc.target {
+                    Target::Section(name) => writeln!(f, "rel {} {}", reloc.offset, name)?,
+                    Target::External(name) => writeln!(f, "xref {} {}", reloc.offset, name)?,
+                }
+            }
+        }
+        for (name, location) in &self.entries {
+            match location {
+                Location::Absolute(addr) => writeln!(f, "entry {} 0x{:02X}", name, addr)?,
+                Location::Relative(sect, offset) => {
+                    writeln!(f, "entry {} {} 0x{:02X}", name, sect, offset)?;
+                }
+            }
+        }
+        for name in &self.externals {
+            writeln!(f, "ext {}", name)?;
+        }
+        Ok(())
+    }
+}
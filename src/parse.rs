@@ -1,94 +1,349 @@
-use crate::node::{Node, Register, Type};
+use crate::control::{branch, condition, inverse, jump, Block, Kind, Part};
+use crate::expr::{constant, unsigned};
+use crate::machine::MEM_SIZE;
+use crate::macros::{Expansion, Macro, MAX_DEPTH};
+use crate::node::{Literal, Node, Register, Type};
 use crate::section::Section;
+use crate::source::Sources;
 use crate::stream::Error;
 use crate::stream::Input;
-use crate::token::Range;
+use crate::symbols::Constant;
 use crate::token::Type as TokenType;
+use crate::token::{Point, Range, Token};
+use crate::warning::{Allowed, Warning};
 use std::ops::Deref;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs::read_to_string;
+use std::path::PathBuf;
 use std::rc::Rc;
 
+/// How many errors are collected before giving up, by default
+pub const MAX_ERRORS: usize = 20;
+
+/// What a register alias stands for
+#[derive(Clone, Copy)]
+enum Alias {
+    Register(Register),
+    /// The stack pointer, which only `ldsa` and `setsp` can reach
+    Stack,
+}
+
+/// Other names for registers, which like the registers themselves can be
+/// written in any case
+const ALIASES: &[(&str, Alias)] = &[
+    ("sp", Alias::Stack),
+    // Conventionally used as the frame pointer
+    ("fp", Alias::Register(3)),
+];
+
+/// Conditional assembly directives
+pub(crate) const CONDITIONALS: &[&str] = &[".if", ".elsif", ".else", ".endif"];
+
+/// Every instruction and directive
+pub(crate) const MNEMONICS: &[&str] = &[
+    "move", "add", "addc", "sub", "and", "or", "xor", "cmp", "not", "neg", "dec", "inc", "shr",
+    "shla", "shra", "rol", "st", "ld", "ldc", "push", "pop", "ldsa", "addsp", "setsp", "pushall",
+    "popall", "ldi", "tst", "clr", "halt", "wait", "jsr", "rts", "ioi", "rti", "crc", "osix",
+    "rand", "beq", "bz", "bne", "bnz", "bhs", "bcs", "blo", "bcc", "bmi", "bpl", "bvs", "bvc",
+    "bhi", "bls", "bge", "blt", "bgt", "ble", "br", "nop", "if", "is", "else", "fi", "while",
+    "stays", "wend", "do", "until", "break", "continue", "dc", "asciz", "ds", "ext", "set", "equ",
+    "asect", "rsect", "tplate", "macro", "mend", "include", "end",
+];
+
+/// A `.if` waiting for its `.endif`
+struct Conditional {
+    /// Where the `.if` is
+    range: Range,
+    /// One of the branches has been assembled
+    taken: bool,
+    /// Where the `.else` is, once it has been seen
+    otherwise: Option<Range>,
+}
+
 pub struct Parser {
     input: Input,
+    /// Files part way through reading when they included another, innermost
+    /// last
+    including: Vec<Input>,
+    /// Every file read so far
+    sources: Sources,
     building: Rc<RefCell<Section>>,
-    rsects: HashMap<String, Rc<RefCell<Section>>>,
+    // Kept in the order they were opened so they are placed predictably
+    rsects: Vec<(String, Rc<RefCell<Section>>)>,
     templates: HashMap<String, Rc<RefCell<Section>>>,
-    asects: Vec<Rc<RefCell<Section>>>
+    asects: Vec<Rc<RefCell<Section>>>,
+    externals: Vec<(String, Range)>,
+    macros: HashMap<String, Macro>,
+    /// Macro invocations being read from, innermost last
+    expansions: Vec<Expansion>,
+    /// Number of expansions so far, used to keep `$label`s unique
+    expanded: usize,
+    /// The outermost invocation the current statement came from, if any
+    site: Option<Range>,
+    /// Every invocation the current statement came from, outermost first
+    calls: Vec<(String, Range)>,
+    /// How deep in invocations the current statement is
+    depth: usize,
+    /// The last token read, the current statement ends with its line
+    last: Option<Range>,
+    /// Structured control flow still waiting to be closed, innermost last
+    blocks: Vec<Block>,
+    /// Number of blocks so far, used to name their labels
+    opened: usize,
+    /// Conditional assembly still waiting for `.endif`, innermost last
+    conditionals: Vec<Conditional>,
+    /// The last label that didn't start with `.`, which `.name` labels
+    /// belong to
+    scope: String,
+    /// How many times each numbered label, such as `1:`, has been defined
+    numbered: HashMap<u8, usize>,
+    /// Each `1f`, as the number, which definition it needs and where it is
+    forward: Vec<(u8, usize, Range)>,
+    /// Named values from `set` and `equ`
+    constants: HashMap<String, Constant>,
+    /// Problems that don't stop assembly, from parsing and compiling
+    warnings: RefCell<Vec<Warning>>,
+    /// Warnings turned off by comments
+    allowed: Allowed,
+    /// Errors collected before `node` gives up
+    max_errors: usize,
+}
+
+macro_rules! no_operand {
+    ( $input:expr, $token:expr, $type:ident ) => {{
+        $input.add(Node::new(Type::$type, $token.range()))?;
+    }};
+}
+
+macro_rules! one_register {
+    ( $input:expr, $token:expr, $type:ident ) => {{
+        let (reg, reg_range) = $input
+            .register()
+            .map_err(|e| e.expected(&format!("{} rn", **$token)))?;
+        $input.add(Node::new(Type::$type(reg), $token.range() + reg_range))?;
+    }};
 }
 
 macro_rules! two_register {
     ( $input:expr, $token:expr, $type:ident ) => {{
-        let (mem, mem_range) = $input.register()?;
-        let c = $input.comma()?;
-        let (reg, reg_range) = $input.register()?;
-        $input.building.borrow_mut().add(Node::new(
+        let form = format!("{} rs, rd", **$token);
+        let (mem, mem_range) = $input.register().map_err(|e| e.expected(&form))?;
+        let c = $input.comma().map_err(|e| e.expected(&form))?;
+        let (reg, reg_range) = $input.register().map_err(|e| e.expected(&form))?;
+        $input.add(Node::new(
             Type::$type(mem, reg),
             $token.range() + mem_range + c + reg_range,
         ))?;
     }};
 }
 
+macro_rules! one_byte {
+    ( $input:expr, $token:expr, $type:ident ) => {{
+        let (byte, byte_range) = $input
+            .byte()
+            .map_err(|e| e.expected(&format!("{} const", **$token)))?;
+        $input.add(Node::new(Type::$type(byte), $token.range() + byte_range))?;
+    }};
+}
+
+macro_rules! one_value {
+    ( $input:expr, $token:expr, $type:ident ) => {{
+        let value = $input
+            .immediate()
+            .map_err(|e| e.expected(&format!("{} const", **$token)))?;
+        let range = $token.range() + value.range();
+        $input.add(Node::new(Type::$type(Box::new(value)), range))?;
+    }};
+}
+
+macro_rules! branch {
+    ( $input:expr, $token:expr, $type:ident ) => {{
+        let target = $input
+            .immediate()
+            .map_err(|e| e.expected(&format!("{} label", **$token)))?;
+        let range = $token.range() + target.range();
+        $input.add(Node::new(Type::$type(Box::new(target)), range))?;
+    }};
+}
+
 impl Parser {
+    /// Parse the main file of `sources`, reading anything it includes into
+    /// them too
     #[must_use]
-    pub fn new(input: Input) -> Self {
+    pub fn new(sources: Sources) -> Self {
+        let text = sources.text(0).unwrap_or_default().to_string();
         Self {
-            input,
+            input: Input::new(text, 0),
+            including: Vec::new(),
+            sources,
             building: Rc::new(RefCell::new(Section::None)),
-            rsects: HashMap::new(),
+            rsects: Vec::new(),
             templates: HashMap::new(),
-            asects: Vec::new()
+            asects: Vec::new(),
+            externals: Vec::new(),
+            macros: HashMap::new(),
+            expansions: Vec::new(),
+            expanded: 0,
+            site: None,
+            calls: Vec::new(),
+            depth: 0,
+            last: None,
+            blocks: Vec::new(),
+            opened: 0,
+            conditionals: Vec::new(),
+            scope: String::new(),
+            numbered: HashMap::new(),
+            forward: Vec::new(),
+            constants: HashMap::new(),
+            warnings: RefCell::new(Vec::new()),
+            allowed: Allowed::default(),
+            max_errors: MAX_ERRORS,
+        }
+    }
+
+    /// The next token, taken from the innermost expansion if there is one
+    fn consume(&mut self) -> Result<Token, Error> {
+        let token = self.next()?;
+        self.last = Some(token.range());
+        Ok(token)
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        while let Some(expansion) = self.expansions.last_mut() {
+            if let Some(token) = expansion.tokens.pop_front() {
+                return Ok(token);
+            }
+            self.expansions.pop();
+        }
+        // The end of an included file carries on after the include
+        loop {
+            let token = self.input.consume()?;
+            if *token == TokenType::Eof {
+                if let Some(outer) = self.including.pop() {
+                    self.input = outer;
+                    continue;
+                }
+            }
+            return Ok(token);
+        }
+    }
+
+    fn peek(&mut self) -> Result<Token, Error> {
+        while let Some(expansion) = self.expansions.last_mut() {
+            if let Some(token) = expansion.tokens.front() {
+                return Ok(token.clone());
+            }
+            self.expansions.pop();
+        }
+        // The end of an included file carries on after the include
+        loop {
+            let token = self.input.peek()?;
+            if *token == TokenType::Eof {
+                if let Some(outer) = self.including.pop() {
+                    self.input = outer;
+                    continue;
+                }
+            }
+            return Ok(token);
+        }
+    }
+
+    /// Does `range` start on the line the current statement ends on
+    fn same_line(&self, range: Range) -> bool {
+        self.last.is_some_and(|last| {
+            range.file() == last.file() && range.start().line() == last.end().line()
+        })
+    }
+
+    /// The next token if it belongs to the current statement, `None` once
+    /// the line ends or another instruction starts
+    fn following(&mut self) -> Result<Option<Token>, Error> {
+        let token = match self.peek() {
+            Ok(token) => token,
+            // A bad character on a later line is left for its own statement
+            Err(err) if !err.at().is_some_and(|at| self.same_line(at)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        match &*token {
+            TokenType::Eof | TokenType::Comment(_) => Ok(None),
+            _ if !self.same_line(token.range()) || is_mnemonic(&token) => Ok(None),
+            _ => Ok(Some(token)),
+        }
+    }
+
+    /// Read the next token of the current statement, which should be
+    /// `expected`. Nothing is read if the statement has ended
+    fn operand(&mut self, expected: &str) -> Result<Token, Error> {
+        self.upcoming(expected)?;
+        self.consume()
+    }
+
+    /// The next token of the current statement without reading it, or an
+    /// error saying `expected` is missing
+    fn upcoming(&mut self, expected: &str) -> Result<Token, Error> {
+        if let Some(token) = self.following()? {
+            return Ok(token);
+        }
+        match (self.peek(), self.last) {
+            (Ok(next), _) if is_mnemonic(&next) && self.same_line(next.range()) => Err(Error::new(
+                format!("Expected {}, got {}", expected, *next),
+                next.range(),
+            )),
+            (_, Some(last)) => Err(Error::new(
+                format!("Expected {}, got the end of the line", expected),
+                last,
+            )),
+            (next, None) => next,
         }
     }
 
     fn register(&mut self) -> Result<(Register, Range), Error> {
-        let token = self.input.consume()?;
-        if let TokenType::Register(reg) = *token {
-            Ok((reg, token.range()))
-        } else {
-            Err(Error::new(
+        let token = self.operand("a register")?;
+        match &*token {
+            TokenType::Register(reg) => Ok((*reg, token.range())),
+            TokenType::Symbol(name) => match alias(name) {
+                Some(Alias::Register(reg)) => Ok((reg, token.range())),
+                Some(Alias::Stack) => Err(Error::new(
+                    format!(
+                        "{} can only be used in ldsa rn, {}+const or ldi {}, const (which is setsp const)",
+                        name, name, name
+                    ),
+                    token.range(),
+                )),
+                None => Err(Error::new(
+                    format!("Expected a register, got {}", *token),
+                    token.range(),
+                )),
+            },
+            _ => Err(Error::new(
                 format!("Expected a register, got {}", *token),
                 token.range(),
-            ))
+            )),
+        }
+    }
+
+    /// Read the stack pointer if it's next, as in `ldi sp, const`
+    fn stack(&mut self) -> Result<Option<Range>, Error> {
+        match self.following()? {
+            Some(token) if matches!(&*token, TokenType::Symbol(name) if matches!(alias(name), Some(Alias::Stack))) =>
+            {
+                self.consume()?;
+                Ok(Some(token.range()))
+            }
+            _ => Ok(None),
         }
     }
 
     fn number(&mut self) -> Result<Node, Error> {
-        let token = self.input.consume()?;
+        let token = self.operand("a number")?;
         match &*token {
-            TokenType::Decimal(num) | TokenType::Hexadecimal(num) | TokenType::Binary(num) => {
-                Ok(Node::new(Type::Unsigned(*num), token.range()))
-            }
-            TokenType::Minus => {
-                let peek = self.input.peek()?;
-                match *peek {
-                    TokenType::Decimal(num) => {
-                        self.input.consume()?;
-                        if num <= 128 {
-                            let num = i8::try_from(
-                                0 - i16::try_from(num).expect("somehow still out of range"),
-                            )
-                            .expect("somehow still out of range");
-                            Ok(Node::new(Type::Signed(num), token.range()))
-                        } else {
-                            Err(Error::new(
-                                format!("Expected a number in range -128->127, got {}", *peek),
-                                token.range(),
-                            ))
-                        }
-                    }
-                    TokenType::Hexadecimal(_) | TokenType::Binary(_) => Err(Error::new(
-                        format!("Only decimal numbers can be signed, not {}", *peek),
-                        token.range() + peek.range(),
-                    )),
-                    _ => Err(Error::new(
-                        format!("Expected an number, got {}", *peek),
-                        token.range(),
-                    )),
-                }
-            }
+            TokenType::Decimal(num)
+            | TokenType::Hexadecimal(num)
+            | TokenType::Binary(num)
+            | TokenType::Octal(num) => Ok(Node::new(Type::Unsigned(*num), token.range())),
             _ => Err(Error::new(
                 format!("Expected an number, got {}", *token),
                 token.range(),
@@ -96,15 +351,137 @@ impl Parser {
         }
     }
 
+    /// A constant expression that has to be known now, such as an `asect`
+    /// address
+    fn byte(&mut self) -> Result<(Literal, Range), Error> {
+        let expr = self.immediate()?;
+        let value = constant(&expr)?;
+        Ok((
+            unsigned(value, expr.range(), self.warnings.get_mut()),
+            expr.range(),
+        ))
+    }
+
+    /// An operand expression: values joined by `+` and `-`
     fn immediate(&mut self) -> Result<Node, Error> {
-        let token = self.input.peek()?;
+        let mut expr = self.unary()?;
+        loop {
+            let op: fn(Box<Node>, Box<Node>) -> Type = match self.following()?.as_deref() {
+                Some(TokenType::Add) => Type::Sum,
+                Some(TokenType::Minus) => Type::Difference,
+                _ => return Ok(expr),
+            };
+            self.consume()?;
+            let rhs = self.unary()?;
+            let range = expr.range() + rhs.range();
+            expr = Node::new(op(Box::new(expr), Box::new(rhs)), range);
+        }
+    }
+
+    /// A value, possibly negated or in brackets
+    fn unary(&mut self) -> Result<Node, Error> {
+        let token = self.upcoming("a value")?;
+        match *token {
+            TokenType::Minus => {
+                self.consume()?;
+                let peek = self.upcoming("a value")?;
+                // Written in any base, such as -0x80
+                if let TokenType::Decimal(num @ 0..=128)
+                | TokenType::Hexadecimal(num @ 0..=128)
+                | TokenType::Binary(num @ 0..=128)
+                | TokenType::Octal(num @ 0..=128) = *peek
+                {
+                    self.consume()?;
+                    let num = i8::try_from(0 - i16::from(num)).expect("somehow still out of range");
+                    return Ok(Node::new(Type::Signed(num), token.range() + peek.range()));
+                }
+                let inner = self.unary()?;
+                let range = token.range() + inner.range();
+                Ok(Node::new(Type::Negate(Box::new(inner)), range))
+            }
+            TokenType::LeftParen => {
+                self.consume()?;
+                let inner = self.immediate()?;
+                let close = self.operand(")")?;
+                if *close == TokenType::RightParen {
+                    Ok(Node::new((*inner).clone(), token.range() + close.range()))
+                } else {
+                    Err(
+                        Error::new(format!("Expected ), got {}", *close), close.range())
+                            .note("To close this (".to_string(), token.range()),
+                    )
+                }
+            }
+            TokenType::Dot => {
+                self.consume()?;
+                Ok(Node::new(Type::Here, token.range()))
+            }
+            _ => self.value(),
+        }
+    }
+
+    /// A single number, symbol or character
+    fn value(&mut self) -> Result<Node, Error> {
+        let token = self.upcoming("a value")?;
         match &*token {
             TokenType::Symbol(sym) => {
-                self.input.consume()?;
-                Ok(Node::new(Type::Label(sym.to_string()), token.range()))
+                self.consume()?;
+                if alias(sym).is_some() {
+                    return Err(Error::new(
+                        format!("{} is a register, not a value", sym),
+                        token.range(),
+                    ));
+                }
+                let sym = self.scoped(sym);
+                // Use the value a constant has now, as it may be set again
+                if let Some(constant) = self.constants.get(&sym) {
+                    return Ok(Node::new(
+                        Type::Constant(sym, constant.value),
+                        token.range(),
+                    ));
+                }
+                Ok(Node::new(Type::Label(sym), token.range()))
+            }
+            TokenType::Backward(num) => {
+                self.consume()?;
+                match self.numbered.get(num) {
+                    Some(count) => Ok(Node::new(
+                        Type::Label(numbered(*num, *count)),
+                        token.range(),
+                    )),
+                    None => Err(Error::new(
+                        format!("There's no {}: before {}", num, *token),
+                        token.range(),
+                    )),
+                }
+            }
+            TokenType::Forward(num) => {
+                self.consume()?;
+                let count = self.numbered.get(num).copied().unwrap_or(0) + 1;
+                self.forward.push((*num, count, token.range()));
+                Ok(Node::new(Type::Label(numbered(*num, count)), token.range()))
+            }
+            TokenType::Register(_) => {
+                self.consume()?;
+                Err(Error::new(
+                    format!("{} is a register, not a value", *token),
+                    token.range(),
+                ))
+            }
+            // A ' that didn't start a character
+            TokenType::Apostrophy => {
+                self.consume()?;
+                Err(Error::new(
+                    "Expected a single character such as 'c' or '\\n'".to_string(),
+                    token.range(),
+                ))
+            }
+            TokenType::Character(byte) => {
+                self.consume()?;
+                Ok(Node::new(Type::Unsigned(*byte), token.range()))
             }
             TokenType::Text(txt) => {
-                self.input.consume()?;
+                self.consume()?;
                 let bytes = txt.as_bytes();
                 if bytes.len() == 1 {
                     Ok(Node::new(Type::Unsigned(bytes[0]), token.range()))
@@ -123,8 +500,83 @@ impl Parser {
         }
     }
 
+    /// A comma separated `dc` list, with `terminate` every string is
+    /// followed by a zero byte
+    fn items(&mut self, terminate: bool) -> Result<Vec<Node>, Error> {
+        let mut items = Vec::new();
+        loop {
+            self.item(&mut items, terminate)?;
+            if self.following()?.as_deref() != Some(&TokenType::Comma) {
+                return Ok(items);
+            }
+            self.consume()?;
+        }
+    }
+
+    /// One `dc` item: a value, a string or `count dup pattern`
+    fn item(&mut self, items: &mut Vec<Node>, terminate: bool) -> Result<(), Error> {
+        let token = self.upcoming("a value")?;
+        if let TokenType::Text(text) = &*token {
+            // A single character is a value, so it can be used in expressions
+            if text.len() != 1 {
+                self.consume()?;
+                items.extend(
+                    text.bytes()
+                        .map(|byte| Node::new(Type::Unsigned(byte), token.range())),
+                );
+                if terminate {
+                    items.push(Node::new(Type::Unsigned(0), token.range()));
+                }
+                return Ok(());
+            }
+        }
+
+        let value = self.immediate()?;
+        if !self
+            .following()?
+            .is_some_and(|token| is_keyword(&token, "dup"))
+        {
+            items.push(value);
+            if terminate && matches!(*token, TokenType::Text(_)) {
+                items.push(Node::new(Type::Unsigned(0), token.range()));
+            }
+            return Ok(());
+        }
+
+        // count dup pattern
+        self.consume()?;
+        let count = usize::try_from(constant(&value)?)
+            .ok()
+            .filter(|count| *count <= MEM_SIZE)
+            .ok_or_else(|| {
+                Error::new(
+                    format!("Repeat count must be 0-{}, got {}", MEM_SIZE, *value),
+                    value.range(),
+                )
+            })?;
+        let mut pattern = Vec::new();
+        let open = self.upcoming("a value")?;
+        if *open == TokenType::LeftParen {
+            self.consume()?;
+            pattern = self.items(terminate)?;
+            let close = self.operand(")")?;
+            if *close != TokenType::RightParen {
+                return Err(
+                    Error::new(format!("Expected ), got {}", *close), close.range())
+                        .note("To close this (".to_string(), open.range()),
+                );
+            }
+        } else {
+            self.item(&mut pattern, terminate)?;
+        }
+        for _ in 0..count {
+            items.extend(pattern.iter().cloned());
+        }
+        Ok(())
+    }
+
     fn comma(&mut self) -> Result<Range, Error> {
-        let token = self.input.consume()?;
+        let token = self.operand("a comma")?;
         if let TokenType::Comma = *token {
             Ok(token.range())
         } else {
@@ -136,9 +588,9 @@ impl Parser {
     }
 
     fn symbol(&mut self) -> Result<String, Error> {
-        let token = self.input.consume()?;
+        let token = self.operand("a symbol")?;
         if let TokenType::Symbol(sym) = &*token {
-            Ok(sym.to_string())
+            Ok(sym.clone())
         } else {
             Err(Error::new(
                 format!("Expected a symbol, got {}", *token),
@@ -147,123 +599,1006 @@ impl Parser {
         }
     }
 
+    fn target(&mut self) -> Result<(String, Range), Error> {
+        // A mnemonic is read as the name, so it's reported as one rather
+        // than starting the next statement
+        let mnemonic =
+            matches!(self.peek(), Ok(next) if is_mnemonic(&next) && self.same_line(next.range()));
+        let token = if mnemonic {
+            self.consume()?
+        } else {
+            self.operand("a label")?
+        };
+        if let TokenType::Symbol(sym) = &*token {
+            Ok((sym.clone(), token.range()))
+        } else {
+            Err(Error::new(
+                format!("Expected a label, got {}", *token),
+                token.range(),
+            ))
+        }
+    }
+
+    /// Read a `macro name/N ... mend` definition, after the `macro`
+    fn define_macro(&mut self, token: &Token) -> Result<(), Error> {
+        let form = "macro name/params";
+        let (name, name_range) = self.target().map_err(|e| e.expected(form))?;
+        let slash = self.operand("/").map_err(|e| e.expected(form))?;
+        if *slash != TokenType::Slash {
+            return Err(
+                Error::new(format!("Expected /, got {}", *slash), slash.range()).expected(form),
+            );
+        }
+        let (params, params_range) = self.byte().map_err(|e| e.expected(form))?;
+        let range = token.range() + name_range + params_range;
+
+        let mut body = Vec::new();
+        // A bad name or token still reads on to mend, so the rest of the body
+        // isn't mistaken for code
+        let mut bad = Self::definable(&name, name_range).err();
+        loop {
+            let token = match self.consume() {
+                Ok(token) => token,
+                Err(err) => {
+                    bad = bad.or(Some(err));
+                    continue;
+                }
+            };
+            match &*token {
+                _ if is_keyword(&token, "mend") => break,
+                _ if is_keyword(&token, "macro") => {
+                    return Err(Error::new(
+                        "Macros can't be defined inside a macro".to_string(),
+                        token.range(),
+                    )
+                    .note(format!("{} starts here", name), range))
+                }
+                TokenType::Eof => {
+                    return Err(Error::new(format!("{} is missing mend", name), range))
+                }
+                TokenType::Comment(_) => (),
+                _ => body.push(token),
+            }
+        }
+
+        if let Some(err) = bad {
+            return Err(err);
+        }
+        let mac = Macro::new(&name, params as usize, body, range)?;
+        if let Some(existing) = self.macros.get(&name) {
+            return Err(
+                Error::new(format!("Macro {} is already defined", name), range)
+                    .note(format!("{} was first defined here", name), existing.range()),
+            );
+        }
+        self.macros.insert(name, mac);
+        Ok(())
+    }
+
+    /// Read `include "file"`, carrying on in that file until it ends
+    fn include(&mut self, token: &Token) -> Result<(), Error> {
+        let name = self
+            .operand("a file name")
+            .map_err(|e| e.expected("include \"file\""))?;
+        let TokenType::Text(file) = &*name else {
+            return Err(
+                Error::new(format!("Expected a file name, got {}", *name), name.range())
+                    .expected("include \"file\""),
+            );
+        };
+        let range = token.range() + name.range();
+        if self.site.is_some() {
+            return Err(Error::new(
+                "Files can't be included by a macro".to_string(),
+                range,
+            ));
+        }
+        let Some(path) = self.sources.resolve(file, range.file()) else {
+            return Err(Error::new(format!("Can't find {}", file), range));
+        };
+        if let Some(open) = self.sources.cycle(&path, range.file()) {
+            let err = Error::new(
+                format!(
+                    "{} is already being read, including it would never end",
+                    file
+                ),
+                range,
+            );
+            return Err(match self.sources.chain(open).first() {
+                Some(first) => err.note(format!("{} was included here", file), *first),
+                None => err,
+            });
+        }
+        let text = read_to_string(&path)
+            .map_err(|e| Error::new(format!("Can't read {}: {}", path.display(), e), range))?;
+        let id = self.sources.add(path, text.clone(), Some(range));
+        let outer = std::mem::replace(&mut self.input, Input::new(text, id));
+        self.including.push(outer);
+        Ok(())
+    }
+
+    /// Every file read, the main one first
+    #[must_use]
+    pub fn into_sources(self) -> Sources {
+        self.sources
+    }
+
+    /// One macro argument, a single token or a negative number
+    fn argument(&mut self) -> Result<Vec<Token>, Error> {
+        let token = self.operand("an argument")?;
+        match *token {
+            TokenType::Minus => Ok(vec![token, self.operand("an argument")?]),
+            TokenType::Comma => Err(Error::new(
+                format!("Expected an argument, got {}", *token),
+                token.range(),
+            )),
+            _ => Ok(vec![token]),
+        }
+    }
+
+    /// Read the arguments to macro `name` and start reading its body
+    fn invoke(&mut self, token: &Token, name: &str) -> Result<(), Error> {
+        let mac = self.macros[name].clone();
+        let form = (1..=mac.params())
+            .map(|n| format!("${}", n))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let form = format!("{} {}", name, form);
+
+        let mut call = token.range();
+        let mut args = Vec::with_capacity(mac.params());
+        for n in 0..mac.params() {
+            if n > 0 {
+                call = call + self.comma().map_err(|e| e.expected(&form))?;
+            }
+            let arg = self.argument().map_err(|e| e.expected(&form))?;
+            if let Some(last) = arg.last() {
+                call = call + last.range();
+            }
+            args.push(arg);
+        }
+
+        if self.depth >= MAX_DEPTH {
+            return Err(Error::new(
+                format!(
+                    "Macros nested more than {} deep expanding {}, does it invoke itself?",
+                    MAX_DEPTH, name
+                ),
+                call,
+            ));
+        }
+        self.expanded += 1;
+        self.expansions.push(Expansion {
+            name: name.to_string(),
+            call,
+            depth: self.depth + 1,
+            tokens: mac.expand(&args, self.expanded),
+        });
+        Ok(())
+    }
+
+    /// Add a node made by lowering structured code
+    fn emit(&mut self, data: Type, range: Range) -> Result<(), Error> {
+        self.add(Node::new(data, range).lowered())
+    }
+
+    /// Add `node` to the current section, remembering which line of the
+    /// source it came from if it was produced by a macro
+    fn add(&mut self, node: Node) -> Result<(), Error> {
+        let node = match self.site {
+            Some(site) => node.expanded_at(site),
+            None => node,
+        };
+        self.building.borrow_mut().add(node)
+    }
+
+    /// Define the constant `name: set value` or `name: equ value`
+    fn constant(&mut self, token: &Token, name: &str, directive: &Token) -> Result<(), Error> {
+        let form = format!("name: {} const", **directive);
+        let expr = self.immediate().map_err(|e| e.expected(&form))?;
+        let range = token.range() + expr.range();
+        let value = constant(&expr)?;
+        let redefinable = is_keyword(directive, "set");
+        if let Some(existing) = self.constants.get(name) {
+            if !(existing.redefinable && redefinable) {
+                let how = if existing.redefinable { "set" } else { "equ" };
+                return Err(Error::new(
+                    format!(
+                        "{} is already defined, only set constants can be set again",
+                        name
+                    ),
+                    range,
+                )
+                .note(
+                    format!("{} was defined with {} here", name, how),
+                    existing.range,
+                ));
+            }
+        }
+        self.constants.insert(
+            name.to_string(),
+            Constant {
+                value,
+                range,
+                redefinable,
+            },
+        );
+        Ok(())
+    }
+
+    /// Define `name` as `value` for the whole file, as `-D name=value` on
+    /// the command line does
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `name` isn't a plain name
+    pub fn define(&mut self, name: &str, value: i32) -> Result<(), Error> {
+        let text = format!("{}={}", name, value);
+        let len = name.chars().count();
+        let file = self
+            .sources
+            .add(PathBuf::from("command line"), text.clone(), None);
+        let range = Range::new(Point::new(1, 0), Point::new(1, len)).in_file(file);
+        // The name has to be a single symbol on its own
+        let mut input = Input::new(name.to_string(), file);
+        let symbol = input.consume().map(|token| (*token).clone());
+        let rest = input.consume().map(|token| (*token).clone());
+        match (symbol, rest) {
+            (Ok(TokenType::Symbol(sym)), Ok(TokenType::Eof)) if sym == name => {
+                Self::definable(name, range)?;
+            }
+            _ => {
+                return Err(Error::new(
+                    format!("{} can't be used as a name", name),
+                    range,
+                ))
+            }
+        }
+        self.constants.insert(
+            name.to_string(),
+            Constant {
+                value,
+                range,
+                redefinable: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// `name` as it's known in the symbol table, `.name` labels belonging
+    /// to the label before them
+    fn scoped(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Check `name`, about to be defined as a label or constant at `range`,
+    /// can be one
+    fn definable(name: &str, range: Range) -> Result<(), Error> {
+        let lower = name.to_lowercase();
+        if MNEMONICS.contains(&lower.as_ref()) || CONDITIONALS.contains(&lower.as_ref()) {
+            return Err(Error::new(
+                format!(
+                    "{} is an instruction or directive, it can't be used as a name",
+                    name
+                ),
+                range,
+            ));
+        }
+        if alias(name).is_some() {
+            return Err(Error::new(
+                format!("{} is a register, it can't be used as a name", name),
+                range,
+            ));
+        }
+        Ok(())
+    }
+
+    /// The value of a `.if` or `.elsif` condition
+    fn test(&mut self, directive: &str) -> Result<bool, Error> {
+        let expr = self
+            .immediate()
+            .map_err(|e| e.expected(&format!("{} const", directive)))?;
+        Ok(constant(&expr)? != 0)
+    }
+
+    /// The conditional a `.elsif`, `.else` or `.endif` at `range` belongs to
+    fn conditional(&mut self, directive: &str, range: Range) -> Result<&mut Conditional, Error> {
+        self.conditionals
+            .last_mut()
+            .ok_or_else(|| Error::new(format!("{} without .if", directive), range))
+    }
+
+    /// Handle conditional assembly `directive` at `range`, returning `false`
+    /// if it isn't one
+    fn condition_directive(&mut self, directive: &str, range: Range) -> Result<bool, Error> {
+        match directive {
+            ".if" => {
+                let test = self.test(directive);
+                // If the condition can't be worked out none of the branches
+                // are assembled
+                self.conditionals.push(Conditional {
+                    range,
+                    taken: test.as_ref().map_or(true, |taken| *taken),
+                    otherwise: None,
+                });
+                if !matches!(test, Ok(true)) {
+                    self.skip()?;
+                }
+                test?;
+            }
+            // The branch before has been assembled, so the rest is skipped
+            ".elsif" | ".else" => {
+                self.branch(directive, range)?;
+                if directive == ".elsif" {
+                    self.immediate().map_err(|e| e.expected(".elsif const"))?;
+                }
+                self.skip()?;
+            }
+            ".endif" => {
+                self.conditional(directive, range)?;
+                self.conditionals.pop();
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Check `.elsif` or `.else` at `range` can follow what came before
+    fn branch(&mut self, directive: &str, range: Range) -> Result<(), Error> {
+        let conditional = self.conditional(directive, range)?;
+        if let Some(otherwise) = conditional.otherwise {
+            return Err(Error::new(format!("{} after .else", directive), range)
+                .note("The .else is here".to_string(), otherwise));
+        }
+        if directive == ".else" {
+            conditional.otherwise = Some(range);
+        }
+        Ok(())
+    }
+
+    /// Skip a branch that isn't assembled, up to the `.elsif`, `.else` or
+    /// `.endif` that ends it. Everything is still split into tokens so
+    /// nested conditionals can be matched up
+    fn skip(&mut self) -> Result<(), Error> {
+        let mut depth = 0;
+        loop {
+            // Code that isn't assembled may not even lex
+            let Ok(token) = self.consume() else {
+                continue;
+            };
+            if *token == TokenType::Eof {
+                let open = self.conditionals.last().map(|c| c.range);
+                // Reported here, as there's nothing left to close them
+                self.conditionals.clear();
+                return Err(Error::new(
+                    ".if is never closed, expected .endif".to_string(),
+                    open.unwrap_or(token.range()),
+                ));
+            }
+            let TokenType::Symbol(directive) = &*token else {
+                continue;
+            };
+            let directive = &directive.to_lowercase();
+            let range = token.range();
+            match directive.as_ref() {
+                ".if" => depth += 1,
+                ".endif" if depth > 0 => depth -= 1,
+                ".endif" => {
+                    self.conditionals.pop();
+                    return Ok(());
+                }
+                ".elsif" | ".else" if depth == 0 => {
+                    self.branch(directive, range)?;
+                    let done = self.conditional(directive, range)?.taken;
+                    let take = match directive.as_ref() {
+                        ".else" => !done,
+                        _ if done => {
+                            self.immediate().map_err(|e| e.expected(".elsif const"))?;
+                            false
+                        }
+                        _ => self.test(directive)?,
+                    };
+                    if take {
+                        self.conditional(directive, range)?.taken = true;
+                        return Ok(());
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Start a new structured block opened at `range`
+    fn open(&mut self, kind: Kind, range: Range) -> Block {
+        self.opened += 1;
+        let block = Block {
+            kind,
+            id: self.opened,
+            range,
+        };
+        self.blocks.push(block.clone());
+        block
+    }
+
+    /// Take the innermost block, which `token` says must be `expected`. A
+    /// block of another kind is still taken, so it's only reported once
+    fn close(&mut self, token: &Token, opener: &str, expected: &[Kind]) -> Result<Block, Error> {
+        match self.blocks.pop() {
+            Some(block) if expected.contains(&block.kind) => Ok(block),
+            Some(block) => Err(Error::new(
+                format!(
+                    "{} expects {} next, not {}",
+                    block.opener(),
+                    block.expecting(),
+                    **token
+                ),
+                block.range,
+            )
+            .note(format!("{} is here", **token), token.range())),
+            None => Err(Error::new(
+                format!("{} without an open {}", **token, opener),
+                token.range(),
+            )),
+        }
+    }
+
+    /// The condition after `is`, `stays` or `until`
+    fn condition(&mut self, form: &str) -> Result<(u8, Range), Error> {
+        let token = self
+            .operand("a condition such as eq, mi or vs")
+            .map_err(|e| e.expected(form))?;
+        if let TokenType::Symbol(name) = &*token {
+            if let Some(cond) = condition(&name.to_lowercase()) {
+                return Ok((cond, token.range()));
+            }
+        }
+        Err(Error::new(
+            format!("Expected a condition such as eq, mi or vs, got {}", *token),
+            token.range(),
+        )
+        .expected(form))
+    }
+
+    /// Lower a structured control flow keyword into branches, returning
+    /// `false` if `keyword` isn't one
+    fn control(&mut self, token: &Token, keyword: &str) -> Result<bool, Error> {
+        let at = token.range();
+        match keyword {
+            "if" => {
+                self.open(Kind::If(Part::Condition), at);
+            }
+            "is" => {
+                let block = self.close(token, "if", &[Kind::If(Part::Condition)])?;
+                let (cond, range) = self.condition("is cond")?;
+                self.emit(
+                    branch(inverse(cond), jump(block.label("else"), at)),
+                    at + range,
+                )?;
+                self.blocks.push(Block {
+                    kind: Kind::If(Part::Body),
+                    ..block
+                });
+            }
+            "else" => {
+                let block = self.close(token, "if", &[Kind::If(Part::Body)])?;
+                self.emit(Type::Br(Box::new(jump(block.label("fi"), at))), at)?;
+                self.emit(Type::Label(block.label("else")), at)?;
+                self.blocks.push(Block {
+                    kind: Kind::If(Part::Else),
+                    ..block
+                });
+            }
+            "fi" => {
+                let block =
+                    self.close(token, "if", &[Kind::If(Part::Body), Kind::If(Part::Else)])?;
+                // Without an else the false branch lands here
+                let end = if block.kind == Kind::If(Part::Body) {
+                    "else"
+                } else {
+                    "fi"
+                };
+                self.emit(Type::Label(block.label(end)), at)?;
+            }
+            "while" => {
+                let block = self.open(Kind::While(Part::Condition), at);
+                self.emit(Type::Label(block.label("while")), at)?;
+            }
+            "stays" => {
+                let block = self.close(token, "while", &[Kind::While(Part::Condition)])?;
+                let (cond, range) = self.condition("stays cond")?;
+                self.emit(
+                    branch(inverse(cond), jump(block.label("wend"), at)),
+                    at + range,
+                )?;
+                self.blocks.push(Block {
+                    kind: Kind::While(Part::Body),
+                    ..block
+                });
+            }
+            "wend" => {
+                let block = self.close(token, "while", &[Kind::While(Part::Body)])?;
+                self.emit(Type::Br(Box::new(jump(block.label("while"), at))), at)?;
+                self.emit(Type::Label(block.label("wend")), at)?;
+            }
+            "do" => {
+                let block = self.open(Kind::Do, at);
+                self.emit(Type::Label(block.label("do")), at)?;
+            }
+            "until" => {
+                let block = self.close(token, "do", &[Kind::Do])?;
+                let (cond, range) = self.condition("until cond")?;
+                self.emit(Type::Label(block.label("until")), at)?;
+                self.emit(
+                    branch(inverse(cond), jump(block.label("do"), at)),
+                    at + range,
+                )?;
+                self.emit(Type::Label(block.label("od")), at)?;
+            }
+            "break" | "continue" => {
+                let target = self
+                    .blocks
+                    .iter()
+                    .rev()
+                    .find_map(|block| {
+                        if keyword == "break" {
+                            block.exit()
+                        } else {
+                            block.next()
+                        }
+                    })
+                    .ok_or_else(|| Error::new(format!("{} outside a loop", keyword), at))?;
+                self.emit(Type::Br(Box::new(jump(target, at))), at)?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Point `err` at every invocation that led to it, once per call site
+    fn in_expansion(&self, mut err: Error) -> Error {
+        let mut last = None;
+        for (name, call) in self.calls.iter().rev() {
+            if last != Some(*call) {
+                err = err.note(format!("In expansion of {}", name), *call);
+                last = Some(*call);
+            }
+        }
+        err
+    }
+
     #[must_use]
     pub fn sections(&self) -> Vec<Rc<RefCell<Section>>> {
         let mut sects = Vec::new();
-        for sect in self.rsects.values() {
+        for (_, sect) in &self.rsects {
             sects.push(Rc::clone(sect));
         }
         for sect in &self.asects {
             sects.push(Rc::clone(sect));
         }
+        for sect in self.templates.values() {
+            sects.push(Rc::clone(sect));
+        }
         sects
     }
 
+    /// Take the warnings found so far
+    pub fn warnings(&self) -> Vec<Warning> {
+        let mut warnings = self.warnings.take();
+        warnings.retain(|warning| !self.allowed.allows(warning));
+        warnings.sort_by_key(|warning| {
+            warning
+                .at()
+                .map(|at| (at.file(), at.start().line(), at.start().column()))
+        });
+        warnings
+    }
+
+    /// Warnings turned off by comments, which also cover warnings from
+    /// linking
+    #[must_use]
+    pub fn allowed(&self) -> &Allowed {
+        &self.allowed
+    }
+
+    /// Record a problem that doesn't stop assembly
+    pub(crate) fn warn(&self, warning: Warning) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    /// Constants defined with `set` or `equ`, with their final values
+    #[must_use]
+    pub fn constants(&self) -> &HashMap<String, Constant> {
+        &self.constants
+    }
+
+    /// Symbols declared with `ext`, to be provided by another unit
+    #[must_use]
+    pub fn externals(&self) -> &[(String, Range)] {
+        &self.externals
+    }
+
+    /// Parse the operands of `mnemonic`, returning `false` if it isn't an instruction
+    fn instruction(&mut self, token: &Token, mnemonic: &str) -> Result<bool, Error> {
+        match mnemonic {
+            "move" => two_register!(self, token, Move),
+            "add" => two_register!(self, token, Add),
+            "addc" => two_register!(self, token, Addc),
+            "sub" => two_register!(self, token, Sub),
+            "and" => two_register!(self, token, And),
+            "or" => two_register!(self, token, Or),
+            "xor" => two_register!(self, token, Xor),
+            "cmp" => two_register!(self, token, Cmp),
+            "not" => one_register!(self, token, Not),
+            "neg" => one_register!(self, token, Neg),
+            "dec" => one_register!(self, token, Dec),
+            "inc" => one_register!(self, token, Inc),
+            "shr" => one_register!(self, token, Shr),
+            "shla" => one_register!(self, token, Shla),
+            "shra" => one_register!(self, token, Shra),
+            "rol" => one_register!(self, token, Rol),
+            "st" => two_register!(self, token, St),
+            "ld" => two_register!(self, token, Ld),
+            "ldc" => two_register!(self, token, Ldc),
+            "push" => one_register!(self, token, Push),
+            "pop" => one_register!(self, token, Pop),
+            "ldsa" => {
+                let form = "ldsa rn, const";
+                let (rn, r) = self.register().map_err(|e| e.expected(form))?;
+                let c = self.comma().map_err(|e| e.expected(form))?;
+                // The offset can be written from the stack pointer, sp+const
+                let offset = match self.stack()? {
+                    Some(sp) => match *self.peek()? {
+                        TokenType::Add => {
+                            self.consume()?;
+                            self.immediate().map_err(|e| e.expected(form))?
+                        }
+                        TokenType::Minus => self.immediate().map_err(|e| e.expected(form))?,
+                        _ => Node::new(Type::Unsigned(0), sp),
+                    },
+                    None => self.immediate().map_err(|e| e.expected(form))?,
+                };
+                let range = token.range() + r + c + offset.range();
+                self.add(Node::new(Type::Ldsa(rn, Box::new(offset)), range))?;
+            }
+            "addsp" => one_value!(self, token, Addsp),
+            "setsp" => one_value!(self, token, Setsp),
+            "pushall" => no_operand!(self, token, Pushall),
+            "popall" => no_operand!(self, token, Popall),
+            "ldi" => {
+                let form = "ldi rn, const";
+                // Loading the stack pointer is setsp
+                if let Some(sp) = self.stack()? {
+                    let c = self.comma().map_err(|e| e.expected(form))?;
+                    let value = self.immediate().map_err(|e| e.expected(form))?;
+                    let range = token.range() + sp + c + value.range();
+                    self.add(Node::new(Type::Setsp(Box::new(value)), range))?;
+                    return Ok(true);
+                }
+                let (rn, r) = self.register().map_err(|e| e.expected(form))?;
+                let c = self.comma().map_err(|e| e.expected(form))?;
+                let lit = self.immediate().map_err(|e| e.expected(form))?;
+                let l = lit.range();
+                self.add(Node::new(
+                    Type::Ldi(rn, Box::new(lit)),
+                    token.range() + r + c + l,
+                ))?;
+            }
+            // tst and clr are the standard CdM-8 macro instructions
+            "tst" => {
+                let (rn, r) = self.register().map_err(|e| e.expected("tst rn"))?;
+                self.add(Node::new(Type::Move(rn, rn), token.range() + r))?;
+            }
+            "clr" => {
+                let (rn, r) = self.register().map_err(|e| e.expected("clr rn"))?;
+                self.add(Node::new(Type::Xor(rn, rn), token.range() + r))?;
+            }
+            "halt" => no_operand!(self, token, Halt),
+            "wait" => no_operand!(self, token, Wait),
+            "jsr" => branch!(self, token, Jsr),
+            "rts" => no_operand!(self, token, Rts),
+            "ioi" => no_operand!(self, token, Ioi),
+            "rti" => no_operand!(self, token, Rti),
+            "crc" => no_operand!(self, token, Crc),
+            "osix" => one_byte!(self, token, Osix),
+            "rand" => no_operand!(self, token, Rand),
+            "beq" | "bz" => branch!(self, token, BeqBz),
+            "bne" | "bnz" => branch!(self, token, BneBnz),
+            "bhs" | "bcs" => branch!(self, token, BhsBcs),
+            "blo" | "bcc" => branch!(self, token, BloBcc),
+            "bmi" => branch!(self, token, Bmi),
+            "bpl" => branch!(self, token, Bpl),
+            "bvs" => branch!(self, token, Bvs),
+            "bvc" => branch!(self, token, Bvc),
+            "bhi" => branch!(self, token, Bhi),
+            "bls" => branch!(self, token, Bls),
+            "bge" => branch!(self, token, Bge),
+            "blt" => branch!(self, token, Blt),
+            "bgt" => branch!(self, token, Bgt),
+            "ble" => branch!(self, token, Ble),
+            "br" => branch!(self, token, Br),
+            "nop" => branch!(self, token, Nop),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Parse the whole input, carrying on after an error from the next line
+    ///
     /// # Errors
     ///
-    pub fn node(&mut self) -> Result<(), Error> {
+    /// Will return `Err` with every error found, up to the maximum. The
+    /// sections still hold everything that did parse
+    pub fn node(&mut self) -> Result<(), Vec<Error>> {
+        let mut errors = Vec::new();
         loop {
-            let token = self.input.consume()?;
-            match &*token {
-                TokenType::Symbol(ref symbol) => match symbol.as_ref() {
-                    "asect" => {
-                        let pos = self.number()?;
-                        if let Type::Unsigned(idx) = *pos {
-                            self.building = Rc::new(RefCell::new(Section::absolute(idx)));
-                            self.asects.push(Rc::clone(&self.building));
-                        } else {
-                            return Err(Error::new(
-                                format!("Expected address, got {}", *pos),
-                                pos.range(),
-                            ));
-                        }
-                    }
-                    "rsect" => {
-                        let name = self.symbol()?;
-                        if let Some(existing) = self.rsects.get(&name) {
-                            self.building = Rc::clone(existing);
-                        } else {
-                            let new = Rc::new(RefCell::new(Section::rsect(name.clone())));
-                            self.rsects.insert(name, Rc::clone(&new));
-                            self.building = new;
-                        }
+            match self.statement() {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(err) => {
+                    let err = self.in_expansion(err);
+                    // Carry on after the invocation rather than in the macro
+                    self.site = None;
+                    self.expansions.clear();
+                    // A bad character may be the first thing on its line
+                    self.last = err.at().or(self.last);
+                    errors.push(err);
+                    if errors.len() >= self.max_errors {
+                        errors.push(Error::general(format!(
+                            "Stopping after {} errors",
+                            errors.len()
+                        )));
+                        return Err(errors);
                     }
-                    "st" => two_register!(self, token, St),
-                    "ld" => two_register!(self, token, Ld),
-                    "add" => two_register!(self, token, Add),
-                    "sub" => two_register!(self, token, Sub),
-                    "or" => two_register!(self, token, Or),
-                    "xor" => two_register!(self, token, Xor),
-                    "and" => two_register!(self, token, And),
-                    "ldi" => {
-                        let (rn, r) = self.register()?;
-                        let c = self.comma()?;
-                        let lit = self.immediate()?;
-                        let l = lit.range();
-                        self.building.borrow_mut().add(Node::new(
-                            Type::Ldi(rn, Box::new(lit)),
-                            token.range() + r + c + l,
-                        ))?;
+                    if !self.resync() {
+                        break;
                     }
-                    "halt" => self
-                        .building
-                        .borrow_mut()
-                        .add(Node::new(Type::Halt, token.range()))?,
-                    "wait" => self
-                        .building
-                        .borrow_mut()
-                        .add(Node::new(Type::Wait, token.range()))?,
-                    "dc" => {
-                        let data = self.immediate()?;
-                        self.building.borrow_mut().add(data)?;
-                    },
-                    "ds" => {
-                        let pos = self.number()?;
-                        if let Type::Unsigned(idx) = *pos {
-                            self.building
-                                .borrow_mut()
-                                .add(Node::new(Type::Ds(idx), token.range() + pos.range()))?;
-                        } else {
-                            return Err(Error::new(
-                                format!("Expected amount, got {}", *pos),
-                                pos.range(),
-                            ));
-                        }
+                }
+            }
+        }
+        for (num, count, range) in &self.forward {
+            if self.numbered.get(num).is_none_or(|defined| defined < count) {
+                errors.push(Error::new(
+                    format!("There's no {}: after {}f", num, num),
+                    *range,
+                ));
+            }
+        }
+        if let Some(conditional) = self.conditionals.last() {
+            errors.push(Error::new(
+                ".if is never closed, expected .endif".to_string(),
+                conditional.range,
+            ));
+        }
+        if let Some(block) = self.blocks.last() {
+            errors.push(Error::new(
+                format!(
+                    "{} is never closed, expected {}",
+                    block.opener(),
+                    block.expecting()
+                ),
+                block.range,
+            ));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Give up after `max` errors
+    pub fn set_max_errors(&mut self, max: usize) {
+        self.max_errors = max.max(1);
+    }
+
+    /// Skip the rest of the statement that went wrong, stopping at the next
+    /// line (or file) or mnemonic. Returns `false` at the end of the input
+    fn resync(&mut self) -> bool {
+        loop {
+            // After a bad character the lexer has moved on, so try again
+            match self.following() {
+                Ok(None) => return !matches!(self.peek().as_deref(), Ok(TokenType::Eof)),
+                Ok(Some(_)) | Err(_) => {
+                    let _ = self.consume();
+                }
+            }
+        }
+    }
+
+    /// Switch to the section opened by `directive`, returning `false` if it
+    /// doesn't open one
+    fn section(&mut self, token: &Token, directive: &str) -> Result<bool, Error> {
+        match directive {
+            "asect" => {
+                let (pos, _) = self.byte().map_err(|e| e.expected("asect address"))?;
+                self.building = Rc::new(RefCell::new(Section::absolute(pos)));
+                self.asects.push(Rc::clone(&self.building));
+            }
+            "rsect" => {
+                let name = self.symbol()?;
+                if let Some((_, existing)) = self.rsects.iter().find(|(n, _)| *n == name) {
+                    self.building = Rc::clone(existing);
+                } else {
+                    let new = Rc::new(RefCell::new(Section::rsect(name.clone())));
+                    self.rsects.push((name, Rc::clone(&new)));
+                    self.building = new;
+                }
+            }
+            "tplate" => {
+                let (name, range) = self.target().map_err(|e| e.expected("tplate name"))?;
+                if self.templates.contains_key(&name) {
+                    return Err(Error::new(
+                        format!("tplate {} is already defined", name),
+                        token.range() + range,
+                    ));
+                }
+                let new = Rc::new(RefCell::new(Section::template(name.clone())));
+                self.templates.insert(name, Rc::clone(&new));
+                self.building = new;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Is the next token the `:` or `>` of a label, on the same line as its
+    /// name
+    fn labels(&mut self) -> Result<bool, Error> {
+        Ok(matches!(
+            self.following()?.as_deref(),
+            Some(TokenType::Colon | TokenType::Gt)
+        ))
+    }
+
+    /// Define `name`, read from `token`, as a label, entry or constant
+    fn label(&mut self, token: &Token, name: &str) -> Result<(), Error> {
+        let mark = self.consume()?;
+        if *mark == TokenType::Colon {
+            let directive = self.peek()?;
+            if let TokenType::Symbol(ref word) = *directive {
+                match word.to_lowercase().as_ref() {
+                    "ext" => {
+                        self.consume()?;
+                        Self::definable(name, token.range())?;
+                        self.externals
+                            .push((name.to_string(), token.range() + directive.range()));
+                        return Ok(());
                     }
-                    "end" => break,
-                    symbol => {
-                        let peek = self.input.peek()?;
-                        match *peek {
-                            TokenType::Colon => {
-                                self.input.consume()?;
-                                self.building.borrow_mut().add(Node::new(
-                                    Type::Label(symbol.to_string()),
-                                    token.range() + peek.range(),
-                                ))?;
-                            }
-                            TokenType::Gt => {
-                                self.input.consume()?;
-                                self.building.borrow_mut().add(Node::new(
-                                    Type::Entry(symbol.to_string()),
-                                    token.range() + peek.range(),
-                                ))?;
-                            }
-                            _ => return Err(Error::new(format!("Unexpected {}", *token), token.range())),
-                        }
+                    "set" | "equ" => {
+                        self.consume()?;
+                        Self::definable(name, token.range())?;
+                        let name = self.scoped(name);
+                        return self.constant(token, &name, &directive);
                     }
-                },
-                TokenType::Comment(_) => (),
-                _ => return Err(Error::new(format!("Unexpected {}", *token), token.range())),
+                    _ => (),
+                }
             }
         }
-        Ok(())
+        Self::definable(name, token.range())?;
+        let scoped = self.scoped(name);
+        // Labels made for macros and structured code don't start a scope
+        if !name.starts_with('.') && !name.contains('$') {
+            self.scope = name.to_string();
+        }
+        let range = token.range() + mark.range();
+        if *mark == TokenType::Colon {
+            self.add(Node::new(Type::Label(scoped), range))
+        } else {
+            self.add(Node::new(Type::Entry(scoped), range))
+        }
+    }
+
+    /// Parse one directive, instruction or label, returning `false` at `end`
+    fn statement(&mut self) -> Result<bool, Error> {
+        let token = self.consume()?;
+        // Reading operands can finish off an expansion, so note it now
+        self.site = self.expansions.first().map(|expansion| expansion.call);
+        self.calls = self
+            .expansions
+            .iter()
+            .map(|expansion| (expansion.name.clone(), expansion.call))
+            .collect();
+        // A macro invoked last in another's body outlives it, so the depth
+        // is carried on rather than counted from what's left
+        self.depth = self
+            .expansions
+            .last()
+            .map_or(0, |expansion| expansion.depth);
+        match &*token {
+            TokenType::Symbol(name) if self.labels()? => self.label(&token, name)?,
+            TokenType::Register(_) if self.labels()? => {
+                return Err(Error::new(
+                    format!("{} is a register, it can't be used as a name", *token),
+                    token.range(),
+                ))
+            }
+            TokenType::Decimal(num) if self.labels()? => {
+                let colon = self.consume()?;
+                if *colon != TokenType::Colon {
+                    return Err(Error::new(
+                        format!(
+                            "Numbered labels can't be exported, expected {}: not {}{}",
+                            num, num, *colon
+                        ),
+                        token.range() + colon.range(),
+                    ));
+                }
+                let count = self.numbered.entry(*num).or_default();
+                *count += 1;
+                let name = numbered(*num, *count);
+                self.add(Node::new(Type::Label(name), token.range() + colon.range()))?;
+            }
+            TokenType::Symbol(name) => match name.to_lowercase().as_ref() {
+                symbol if self.condition_directive(symbol, token.range())? => (),
+                symbol if self.section(&token, symbol)? => (),
+                "dc" | "asciz" => {
+                    let form = format!("{} value, \"text\", count dup value, ...", *token);
+                    let data = self
+                        .items(name.eq_ignore_ascii_case("asciz"))
+                        .map_err(|e| e.expected(&form))?;
+                    let range = data
+                        .last()
+                        .map_or(token.range(), |last| token.range() + last.range());
+                    self.add(Node::new(Type::Dc(data), range))?;
+                }
+                "ds" => {
+                    let (amount, range) = self.byte().map_err(|e| e.expected("ds amount"))?;
+                    self.add(Node::new(Type::Ds(amount), token.range() + range))?;
+                }
+                "ext" => {
+                    let (name, range) = self.target().map_err(|e| e.expected("ext name"))?;
+                    Self::definable(&name, range)?;
+                    self.externals.push((name, token.range() + range));
+                }
+                "macro" => self.define_macro(&token)?,
+                "include" => self.include(&token)?,
+                "end" => return Ok(false),
+                symbol if self.control(&token, symbol)? => (),
+                symbol if self.instruction(&token, symbol)? => (),
+                _ if self.macros.contains_key(name) => self.invoke(&token, name)?,
+                _ => return Err(Error::new(format!("Unexpected {}", *token), token.range())),
+            },
+            TokenType::Comment(text) => self.allowed.comment(text, token.range())?,
+            _ => return Err(Error::new(format!("Unexpected {}", *token), token.range())),
+        }
+        Ok(true)
     }
 }
 
+/// What `name` is an alias for, if anything
+fn alias(name: &str) -> Option<Alias> {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| name.eq_ignore_ascii_case(alias))
+        .map(|(_, alias)| *alias)
+}
+
+/// Is `token` an instruction or directive, which starts a new statement
+fn is_mnemonic(token: &Token) -> bool {
+    matches!(&**token, TokenType::Symbol(sym) if {
+        let lower = sym.to_lowercase();
+        MNEMONICS.contains(&lower.as_ref()) || CONDITIONALS.contains(&lower.as_ref())
+    })
+}
+
+/// Is `token` the keyword `word`, in any case
+fn is_keyword(token: &Token, word: &str) -> bool {
+    matches!(&**token, TokenType::Symbol(sym) if sym.eq_ignore_ascii_case(word))
+}
+
+/// The name given to definition `count` of numbered label `num`
+fn numbered(num: u8, count: usize) -> String {
+    format!("{}${}", num, count)
+}
+
 impl Deref for Parser {
-    type Target = Input;
+    type Target = Sources;
 
     fn deref(&self) -> &Self::Target {
-        &self.input
+        &self.sources
     }
 }
@@ -1,4 +1,4 @@
-use crate::node::Node;
+use crate::node::{Node, Type};
 use crate::Error;
 
 use std::fmt;
@@ -12,6 +12,7 @@ pub enum Section {
 }
 
 impl Section {
+    #[must_use]
     pub fn absolute(pos: u8) -> Self {
         Self::Absolute {
             pos,
@@ -19,6 +20,7 @@ impl Section {
         }
     }
 
+    #[must_use]
     pub fn rsect(name: String) -> Self {
         Self::RSect {
             name,
@@ -26,11 +28,34 @@ impl Section {
         }
     }
 
+    #[must_use]
+    pub fn template(name: String) -> Self {
+        Self::Template {
+            name,
+            content: Vec::with_capacity(10),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if there isn't an open section or `node` isn't a
+    /// field definition in a template
     pub fn add(&mut self, node: Node) -> Result<(), Error> {
         match self {
-            Self::Absolute { content, .. }
-            | Self::RSect { content, .. }
-            | Self::Template { content, .. } => {
+            Self::Template { name, content } => match *node {
+                Type::Label(_) | Type::Ds(_) | Type::Dc(_) => {
+                    content.push(node);
+                    Ok(())
+                }
+                _ => Err(Error::new(
+                    format!(
+                        "tplate {} can only hold labels, dc and ds, not {}",
+                        name, *node
+                    ),
+                    node.range(),
+                )),
+            },
+            Self::Absolute { content, .. } | Self::RSect { content, .. } => {
                 content.push(node);
                 Ok(())
             }
@@ -42,21 +67,21 @@ impl Section {
 impl fmt::Display for Section {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Absolute{pos, content} => {
+            Self::Absolute { pos, content } => {
                 writeln!(f, "asect {}", pos)?;
                 for node in content {
                     writeln!(f, "  {}", **node)?;
                 }
                 Ok(())
             }
-            Self::RSect{name, content} => {
+            Self::RSect { name, content } => {
                 writeln!(f, "rsect {}", name)?;
                 for node in content {
                     writeln!(f, "  {}", **node)?;
                 }
                 Ok(())
             }
-            Self::Template{name, content} => {
+            Self::Template { name, content } => {
                 writeln!(f, "tplate {}", name)?;
                 for node in content {
                     writeln!(f, "  {}", **node)?;
@@ -68,4 +93,4 @@ impl fmt::Display for Section {
             }
         }
     }
-}
\ No newline at end of file
+}
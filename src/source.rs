@@ -0,0 +1,154 @@
+use crate::token::Range;
+
+use std::path::{Path, PathBuf};
+
+/// The number of a file within `Sources`
+pub type FileId = usize;
+
+#[derive(Clone)]
+struct File {
+    path: PathBuf,
+    text: String,
+    /// The `include` that read this file, `None` for the main file
+    included: Option<Range>,
+}
+
+/// Every file read while assembling one unit, numbered in the order they
+/// are read with the main file as 0
+#[derive(Default)]
+pub struct Sources {
+    files: Vec<File>,
+    /// Directories searched for included files after the including file's own
+    search: Vec<PathBuf>,
+}
+
+impl Sources {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `text`, read from `path` by the `include` at `included`
+    pub fn add(&mut self, path: PathBuf, text: String, included: Option<Range>) -> FileId {
+        self.files.push(File {
+            path,
+            text,
+            included,
+        });
+        self.files.len() - 1
+    }
+
+    /// Add every file of `other` after these ones, returning the number its
+    /// first file now has. Ranges into `other` need moving on by that much
+    pub fn append(&mut self, other: &Sources) -> FileId {
+        let base = self.files.len();
+        self.files.extend(other.files.iter().map(|file| File {
+            included: file.included.map(|at| at.in_file(at.file() + base)),
+            ..file.clone()
+        }));
+        base
+    }
+
+    /// Also look in `dir` for included files
+    pub fn search(&mut self, dir: PathBuf) {
+        self.search.push(dir);
+    }
+
+    #[must_use]
+    pub fn path(&self, file: FileId) -> Option<&Path> {
+        self.files.get(file).map(|file| file.path.as_path())
+    }
+
+    #[must_use]
+    pub fn text(&self, file: FileId) -> Option<&str> {
+        self.files.get(file).map(|file| file.text.as_str())
+    }
+
+    /// The text `range` covers, if it's all on one line
+    #[must_use]
+    pub fn written(&self, range: Range) -> Option<String> {
+        if range.start().line() != range.end().line() {
+            return None;
+        }
+        let line = self
+            .text(range.file())?
+            .split('\n')
+            .nth(range.start().line().checked_sub(1)?)?;
+        Some(
+            line.chars()
+                .skip(range.start().column())
+                .take(range.end().column() - range.start().column())
+                .collect(),
+        )
+    }
+
+    /// The `include`s that led to `file`, innermost first
+    #[must_use]
+    pub fn chain(&self, mut file: FileId) -> Vec<Range> {
+        let mut chain = Vec::new();
+        while let Some(at) = self.files.get(file).and_then(|file| file.included) {
+            chain.push(at);
+            file = at.file();
+        }
+        chain
+    }
+
+    /// The range in the main file that `at` came from, the outermost
+    /// `include` if it's in another file
+    #[must_use]
+    pub fn outermost(&self, at: Range) -> Range {
+        self.chain(at.file()).last().copied().unwrap_or(at)
+    }
+
+    /// Where `range` comes in the order the source is read, as the line and
+    /// column of each `include` leading to it (outermost first) followed by
+    /// its own
+    #[must_use]
+    pub fn order(&self, range: Range) -> Vec<(usize, usize)> {
+        self.chain(range.file())
+            .iter()
+            .rev()
+            .chain(std::iter::once(&range))
+            .map(|at| (at.start().line(), at.start().column()))
+            .collect()
+    }
+
+    /// Find `name`, included from `from`, next to that file or else in the
+    /// search directories
+    #[must_use]
+    pub fn resolve(&self, name: &str, from: FileId) -> Option<PathBuf> {
+        let beside = self
+            .path(from)
+            .and_then(Path::parent)
+            .map(|dir| dir.join(name));
+        beside
+            .into_iter()
+            .chain(self.search.iter().map(|dir| dir.join(name)))
+            .find(|path| path.is_file())
+    }
+
+    /// The file already open as `path` somewhere in the chain leading to
+    /// `from`, if including it again would never finish
+    #[must_use]
+    pub fn cycle(&self, path: &Path, from: FileId) -> Option<FileId> {
+        let same = |file: FileId| {
+            self.path(file)
+                .is_some_and(|other| match (other.canonicalize(), path.canonicalize()) {
+                    (Ok(a), Ok(b)) => a == b,
+                    _ => other == path,
+                })
+        };
+        std::iter::once(from)
+            .chain(self.chain(from).iter().map(Range::file))
+            .find(|file| same(*file))
+    }
+}
+
+impl From<String> for Sources {
+    /// Just `text`, as if read from a file called `input`
+    fn from(text: String) -> Self {
+        let mut sources = Self::new();
+        sources.add(PathBuf::from("input"), text, None);
+        sources
+    }
+}
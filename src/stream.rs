@@ -2,18 +2,27 @@ use std::convert::TryFrom;
 use std::error;
 use std::fmt;
 
+use crate::source::{FileId, Sources};
 use crate::token::Point;
 use crate::token::{Range, Token, Type};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Error {
     message: String,
-    at: Range,
+    at: Option<Range>,
+    notes: Vec<(String, Range)>,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} {}", self.message, self.at)
+        write!(f, "{}", self.message)?;
+        if let Some(at) = self.at {
+            write!(f, " {}", at)?;
+        }
+        for (message, at) in &self.notes {
+            write!(f, "; {} {}", message, at)?;
+        }
+        Ok(())
     }
 }
 
@@ -26,70 +35,130 @@ impl error::Error for Error {
 impl Error {
     #[must_use]
     pub fn new(message: String, at: Range) -> Self {
-        Self { message, at }
+        Self {
+            message,
+            at: Some(at),
+            notes: Vec::new(),
+        }
     }
 
+    /// An error that isn't tied to a place in the source
     #[must_use]
-    pub fn at(&self) -> Range {
+    pub fn general(message: String) -> Self {
+        Self {
+            message,
+            at: None,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Extend the message with the form that was expected
+    #[must_use]
+    pub fn expected(mut self, form: &str) -> Self {
+        self.message = format!("{}, format is {}", self.message, form);
+        self
+    }
+
+    #[must_use]
+    pub fn at(&self) -> Option<Range> {
         self.at
     }
 
-    pub fn print(&self, src: Option<&Input>) {
-        if let Some(src) = src {
-            let line = format!("{}", self.at.start().line());
-            let lines: Vec<&str> = src.input.split('\n').collect();
-            if lines.len() >= self.at.start().line() {
-                eprintln!("{} ❘{}", line, lines[self.at.start().line() - 1]);
-            } else {
-                eprintln!("{} ❘ [err]", line);
+    /// Attach a related location, such as an earlier definition
+    #[must_use]
+    pub fn note(mut self, message: String, at: Range) -> Self {
+        self.notes.push((message, at));
+        self
+    }
+
+    #[must_use]
+    pub fn notes(&self) -> &[(String, Range)] {
+        &self.notes
+    }
+
+    /// `message` under the line of `sources` that `at` is on
+    fn show(sources: &Sources, message: &str, at: Range) {
+        let line = format!("{}", at.start().line());
+        if let Some(path) = sources.path(at.file()) {
+            eprintln!("{}:{}:{}", path.display(), line, at.start().column() + 1);
+        }
+        match sources
+            .text(at.file())
+            .and_then(|text| text.split('\n').nth(at.start().line().wrapping_sub(1)))
+        {
+            Some(text) => eprintln!("{} ❘{}", line, text),
+            None => eprintln!("{} ❘ [err]", line),
+        }
+        eprintln!(
+            "{:idt$} ❘{:pad$}{:↑>num$}",
+            " ",
+            "",
+            "↑",
+            idt = line.len(),
+            pad = at.start().column(),
+            num = at.end().column().saturating_sub(at.start().column())
+        );
+        eprintln!(
+            "{:idt$} ❘{:pad$}{}",
+            " ",
+            " ",
+            message,
+            idt = line.len(),
+            pad = at.start().column()
+        );
+        for include in sources.chain(at.file()) {
+            if let Some(path) = sources.path(include.file()) {
+                eprintln!(
+                    "{:idt$} ❘ included from {}:{}",
+                    " ",
+                    path.display(),
+                    include.start().line(),
+                    idt = line.len()
+                );
+            }
+        }
+    }
+
+    /// Show the error against the source it's in, or just describe it
+    /// without `src`
+    pub fn print(&self, src: Option<&Sources>) {
+        if let (Some(src), Some(at)) = (src, self.at) {
+            Self::show(src, &self.message, at);
+            for (message, at) in &self.notes {
+                Self::show(src, message, *at);
             }
-            eprintln!(
-                "{:idt$} ❘{:pad$}{:↑>num$}",
-                " ",
-                "",
-                "↑",
-                idt = line.len(),
-                pad = self.at.start().column(),
-                num = self.at.end().column() - self.at.start().column()
-            );
-            eprintln!(
-                "{:idt$} ❘{:pad$}{}",
-                " ",
-                " ",
-                self.message,
-                idt = line.len(),
-                pad = self.at.start().column()
-            );
         } else {
-            eprintln!("{}", self.message);
+            eprintln!("{}", self);
         }
     }
 }
 
 pub struct Input {
-    input: String,
+    source: String,
+    file: FileId,
     line: usize,
     col: usize,
     pos: usize,
-    current: Option<Token>,
+    /// What `peek` saw, a bad character stays until it's consumed
+    current: Option<Result<Token, Error>>,
 }
 
 macro_rules! token {
     ( $input:expr, $start:expr, $type:expr ) => {{
-        Ok(Token::new($type, Range::new($start, $input.here())))
+        Ok(Token::new($type, $input.range($start)))
     }};
 }
 
 macro_rules! char_token {
     ( $input:expr, $start:expr, $type:expr ) => {{
         $input.forward();
-        Ok(Token::new($type, Range::new($start, $input.here())))
+        Ok(Token::new($type, $input.range($start)))
     }};
 }
 
 impl Input {
     fn forward(&mut self) {
-        if let Some(ch) = self.input.chars().nth(self.pos) {
+        if let Some(ch) = self.source.chars().nth(self.pos) {
             self.pos += 1;
             if ch == '\n' {
                 self.line += 1;
@@ -101,13 +170,18 @@ impl Input {
     }
 
     fn peek_char(&self) -> Option<char> {
-        self.input.chars().nth(self.pos)
+        self.source.chars().nth(self.pos)
     }
 
     fn here(&self) -> Point {
         Point::new(self.line, self.col)
     }
 
+    /// From `start` up to here
+    fn range(&self, start: Point) -> Range {
+        Range::new(start, self.here()).in_file(self.file)
+    }
+
     fn read(&mut self, matcher: &dyn Fn(char) -> bool) -> String {
         let mut s = String::new();
         while let Some(ch) = self.peek_char() {
@@ -121,100 +195,221 @@ impl Input {
         s
     }
 
-    fn read_hex(&mut self, start: Point) -> Result<Token, Error> {
-        self.forward();
-        let hex: Vec<_> = self
-            .read(&|c| c.is_digit(16))
+    /// The digits of a number in `radix` after a prefix such as `0x`,
+    /// which can be split up with `_`
+    fn read_radix(&mut self, start: Point, radix: u32, prefix: &str) -> Result<u8, Error> {
+        // Read anything that could be part of the number so a stray letter
+        // is reported rather than starting a new symbol
+        let digits = self.read(&|c| c.is_alphanumeric() || c == '_');
+        let written = format!("{}{}", prefix, digits);
+        if let Some(bad) = digits.chars().find(|c| !c.is_digit(radix) && *c != '_') {
+            return Err(Error::new(
+                format!("{} isn't a digit in {}", bad, written),
+                self.range(start),
+            ));
+        }
+        if !digits.chars().any(|c| c.is_digit(radix)) {
+            return Err(Error::new(
+                format!("Expected digits after {}", prefix),
+                self.range(start),
+            ));
+        }
+        self.fit(start, &written, &digits, radix)
+    }
+
+    /// The value of `digits` in `radix`, as long as it fits in a byte
+    fn fit(&self, start: Point, written: &str, digits: &str, radix: u32) -> Result<u8, Error> {
+        let value = digits
             .chars()
-            .map(|ch| {
-                u8::try_from(ch.to_digit(16).expect("Should have been hex"))
-                    .expect("Somehow still out of range hex")
-            })
-            .collect();
-        if hex.len() == 2 {
-            let byte = (hex[0] << 4) | hex[1];
-            token!(self, start, Type::Hexadecimal(byte))
-        } else {
-            Err(Error::new(
-                format!("Expected 2 digits, got {}", hex.len()),
-                Range::new(start, self.here()),
-            ))
+            .filter_map(|c| c.to_digit(radix))
+            .try_fold(0_u32, |value, digit| {
+                value.checked_mul(radix)?.checked_add(digit)
+            });
+        match value.map(u8::try_from) {
+            Some(Ok(byte)) => Ok(byte),
+            Some(Err(_)) if digits == written && radix == 10 && !digits.contains('_') => {
+                Err(Error::new(
+                    format!("Expected a number in range 0-255, got {}", written),
+                    self.range(start),
+                ))
+            }
+            Some(Err(_)) => Err(Error::new(
+                format!(
+                    "{} is {}, expected a number in range 0-255",
+                    written,
+                    value.unwrap_or_default()
+                ),
+                self.range(start),
+            )),
+            None => Err(Error::new(
+                format!(
+                    "{} is far too big, expected a number in range 0-255",
+                    written
+                ),
+                self.range(start),
+            )),
         }
     }
 
-    fn read_bin(&mut self, start: Point) -> Result<Token, Error> {
+    /// A name, `r0` to `r3` (in either case) being registers
+    fn read_symbol(&mut self, start: Point) -> Result<Token, Error> {
+        let text = self.read(&is_identifier);
+        match text.to_lowercase().as_ref() {
+            "r0" => token!(self, start, Type::Register(0)),
+            "r1" => token!(self, start, Type::Register(1)),
+            "r2" => token!(self, start, Type::Register(2)),
+            "r3" => token!(self, start, Type::Register(3)),
+            _ => token!(self, start, Type::Symbol(text)),
+        }
+    }
+
+    /// The character `n` places after the next one
+    fn ahead(&self, n: usize) -> Option<char> {
+        self.source.chars().nth(self.pos + n)
+    }
+
+    /// A decimal number, or a reference to a numbered label such as `1b`
+    fn read_decimal(&mut self, start: Point) -> Result<Token, Error> {
+        let digits = self.read(&|c| c.is_ascii_digit() || c == '_');
+        let num = self.fit(start, &digits, &digits, 10)?;
+        let direction = self.peek_char();
+        if matches!(direction, Some('b' | 'f')) && !self.ahead(1).is_some_and(is_identifier) {
+            self.forward();
+            return if direction == Some('b') {
+                token!(self, start, Type::Backward(num))
+            } else {
+                token!(self, start, Type::Forward(num))
+            };
+        }
+        token!(self, start, Type::Decimal(num))
+    }
+
+    fn read_zero_prefix(&mut self, start: Point) -> Result<Token, Error> {
+        let prefix = self.ahead(1).map(|c| c.to_ascii_lowercase());
+        let radix = match prefix {
+            Some('x') => 16,
+            Some('o') => 8,
+            // 0b on its own refers back to label 0
+            Some('b') if self.ahead(2).is_some_and(|c| c.is_digit(2)) => 2,
+            _ => return self.read_decimal(start),
+        };
         self.forward();
-        let bin = self.read(&|c| c.is_digit(2));
-        if bin.len() == 8 {
-            let byte = bin
-                .chars()
-                .map(|ch| {
-                    u8::try_from(ch.to_digit(2).expect("Should have been bin"))
-                        .expect("Somehow still out of range binary")
-                })
-                .rev()
-                .enumerate()
-                .fold(0, |byte, (n, bit)| byte | (bit << n));
-            token!(self, start, Type::Binary(byte))
-        } else {
-            Err(Error::new(
-                format!("Expected 8 bits, got {}", bin.len()),
-                Range::new(start, self.here()),
-            ))
+        self.forward();
+        let prefix = format!("0{}", prefix.unwrap_or_default());
+        let num = self.read_radix(start, radix, &prefix)?;
+        match radix {
+            16 => token!(self, start, Type::Hexadecimal(num)),
+            8 => token!(self, start, Type::Octal(num)),
+            _ => token!(self, start, Type::Binary(num)),
         }
     }
 
-    fn read_register(&mut self, start: Point) -> Result<Token, Error> {
+    /// The character after a `\\` in a string or character
+    fn escape(&mut self, start: Point) -> Result<char, Error> {
         self.forward();
-        if let Some(reg) = self.peek_char() {
-            self.forward();
-            match reg {
-                '0' => token!(self, start, Type::Register(0)),
-                '1' => token!(self, start, Type::Register(1)),
-                '2' => token!(self, start, Type::Register(2)),
-                '3' => token!(self, start, Type::Register(3)),
-                _ => {
-                    let text = self.read(&|c| c.is_alphanumeric());
-                    token!(self, start, Type::Symbol(format!("r{}{}", reg, text)))
+        let ch = match self.peek_char() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(ch @ ('"' | '\'' | '\\')) => ch,
+            Some(ch) => {
+                self.forward();
+                return Err(Error::new(
+                    format!("Unknown escape \\{}", ch),
+                    self.range(start),
+                ));
+            }
+            None => {
+                return Err(Error::new(
+                    "Unexpected end of file".to_string(),
+                    self.range(start),
+                ))
+            }
+        };
+        self.forward();
+        Ok(ch)
+    }
+
+    fn read_text(&mut self, start: Point) -> Result<Token, Error> {
+        self.forward();
+        let mut text = String::new();
+        loop {
+            match self.peek_char() {
+                Some('"') => {
+                    self.forward();
+                    return token!(self, start, Type::Text(text));
+                }
+                Some('\\') => {
+                    let at = self.here();
+                    text.push(self.escape(at)?);
+                }
+                Some(ch) => {
+                    text.push(ch);
+                    self.forward();
+                }
+                None => {
+                    return Err(Error::new(
+                        "Unterminated string".to_string(),
+                        self.range(start),
+                    ))
                 }
             }
-        } else {
-            Err(Error::new(
-                "Unexpected end of file".to_string(),
-                Range::new(start, self.here()),
-            ))
         }
     }
 
-    fn read_zero_prefix(&mut self, start: Point) -> Result<Token, Error> {
+    /// A character such as `'a'` or `'\\n'`, or just an apostrophe
+    fn read_character(&mut self, start: Point) -> Result<Token, Error> {
+        let closed = match (self.ahead(1), self.ahead(2), self.ahead(3)) {
+            (Some('\\'), Some(_), Some('\'')) => true,
+            (Some(ch), Some('\''), _) => ch != '\\' && ch != '\'',
+            _ => false,
+        };
+        self.forward();
+        if !closed {
+            return token!(self, start, Type::Apostrophy);
+        }
+        let ch = match self.peek_char() {
+            Some('\\') => {
+                let at = self.here();
+                self.escape(at)?
+            }
+            Some(ch) => {
+                self.forward();
+                ch
+            }
+            None => unreachable!("Checked above"),
+        };
+        self.forward();
+        match u8::try_from(u32::from(ch)) {
+            Ok(byte) => token!(self, start, Type::Character(byte)),
+            Err(_) => Err(Error::new(
+                format!("{} doesn't fit in a byte", ch),
+                self.range(start),
+            )),
+        }
+    }
+
+    fn read_dollar(&mut self, start: Point) -> Result<Token, Error> {
         self.forward();
         match self.peek_char() {
-            Some('x') => self.read_hex(start),
-            Some('b') => self.read_bin(start),
-            Some(_) => {
-                let num = format!("0{}", self.read(&|ch| ch.is_digit(10)));
-                let num = num
-                    .chars()
-                    .map(|ch| ch.to_digit(10).expect("Should have been decimal"))
-                    .rev()
-                    .enumerate()
-                    .fold(0, |num, (n, digit)| num + (digit * (10_u32.pow(n as u32))));
-                if num > 255 {
-                    Err(Error::new(
-                        format!("Expected number in range 0-255, got {}", num),
-                        Range::new(start, self.here()),
-                    ))
-                } else {
-                    token!(
-                        self,
-                        start,
-                        Type::Decimal(u8::try_from(num).expect("Somehow still out of range"))
-                    )
+            Some(ch) if ch.is_ascii_digit() => {
+                let num = self.read(&|c| c.is_ascii_digit());
+                match num.parse() {
+                    Ok(num) => token!(self, start, Type::Parameter(num)),
+                    Err(_) => Err(Error::new(
+                        format!("Parameter ${} is out of range", num),
+                        self.range(start),
+                    )),
                 }
             }
-            None => Err(Error::new(
-                "Unexpected end of file".to_string(),
-                Range::new(start, self.here()),
+            Some(ch) if ch.is_alphabetic() || ch == '_' => {
+                let text = self.read(&is_identifier);
+                token!(self, start, Type::Local(text))
+            }
+            _ => Err(Error::new(
+                "Expected a parameter number or label after $".to_string(),
+                self.range(start),
             )),
         }
     }
@@ -224,62 +419,38 @@ impl Input {
         let start = self.here();
         if let Some(ch) = self.peek_char() {
             match ch {
-                'r' => self.read_register(start),
                 '0' => self.read_zero_prefix(start),
                 ',' => char_token!(self, start, Type::Comma),
                 '-' => char_token!(self, start, Type::Minus),
                 '+' => char_token!(self, start, Type::Add),
                 ':' => char_token!(self, start, Type::Colon),
+                '_' if self.ahead(1).is_some_and(is_identifier) => self.read_symbol(start),
+                // .name is a local label or directive, . on its own is here
+                '.' if self.ahead(1).is_some_and(|c| c.is_alphabetic() || c == '_') => {
+                    self.read_symbol(start)
+                }
                 '_' => char_token!(self, start, Type::Underscore),
                 '>' => char_token!(self, start, Type::Gt),
-                '\'' => char_token!(self, start, Type::Apostrophy),
+                '\'' => self.read_character(start),
                 '/' => char_token!(self, start, Type::Slash),
                 '?' => char_token!(self, start, Type::Question),
                 '!' => char_token!(self, start, Type::Exclame),
                 '.' => char_token!(self, start, Type::Dot),
-                '"' => {
-                    self.forward();
-                    let text = self.read(&|c| c != '"');
-                    self.forward();
-                    token!(self, start, Type::Text(text))
-                }
+                '(' => char_token!(self, start, Type::LeftParen),
+                ')' => char_token!(self, start, Type::RightParen),
+                '$' => self.read_dollar(start),
+                '"' => self.read_text(start),
                 '#' => {
                     self.forward();
                     let text = self.read(&|c| c != '\n');
                     self.forward();
                     token!(self, start, Type::Comment(text))
                 }
-                ch if ch.is_digit(10) => {
-                    let num = self.read(&|c| c.is_digit(10));
-                    let num = num
-                        .chars()
-                        .map(|ch| ch.to_digit(10).expect("Should have been deciman"))
-                        .rev()
-                        .enumerate()
-                        .fold(0, |num, (n, digit)| num + (digit * (10_u32.pow(n as u32))));
-                    if num > 255 {
-                        Err(Error::new(
-                            format!("Expected number in range 0-255, got {}", num),
-                            Range::new(start, self.here()),
-                        ))
-                    } else {
-                        token!(
-                            self,
-                            start,
-                            Type::Decimal(u8::try_from(num).expect("Somehow still out of range"))
-                        )
-                    }
-                }
-                ch if ch.is_alphabetic() => {
-                    let text = self.read(&|c| c.is_alphanumeric());
-                    token!(self, start, Type::Symbol(text))
-                }
+                ch if ch.is_ascii_digit() => self.read_decimal(start),
+                ch if ch.is_alphabetic() => self.read_symbol(start),
                 ch => {
                     self.forward();
-                    Err(Error::new(
-                        format!("Unknown {}", ch),
-                        Range::new(start, self.here()),
-                    ))
+                    Err(Error::new(format!("Unknown {}", ch), self.range(start)))
                 }
             }
         } else {
@@ -291,33 +462,53 @@ impl Input {
     ///
     pub fn peek(&mut self) -> Result<Token, Error> {
         if self.current.is_none() {
-            self.current = Some(self.read_next()?);
+            self.current = Some(self.read_next());
         }
-        if let Some(token) = self.current.clone() {
-            Ok(token)
-        } else {
-            Err(Error::new(
+        match &self.current {
+            Some(next) => next.clone(),
+            None => Err(Error::new(
                 "Unexpected end of input".to_string(),
-                Range::new(self.here(), self.here()),
-            ))
+                self.range(self.here()),
+            )),
         }
     }
 
     /// # Errors
     ///
     pub fn consume(&mut self) -> Result<Token, Error> {
-        if let Some(tok) = self.current.take() {
-            Ok(tok)
+        if let Some(next) = self.current.take() {
+            next
         } else {
             self.read_next()
         }
     }
 }
 
+/// Can `ch` be part of a name
+fn is_identifier(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_' || ch == '.'
+}
+
+impl Input {
+    /// Read `source`, the text of `file`
+    #[must_use]
+    pub fn new(source: String, file: FileId) -> Self {
+        Self {
+            source,
+            file,
+            line: 1,
+            col: 0,
+            pos: 0,
+            current: None,
+        }
+    }
+}
+
 impl From<String> for Input {
     fn from(input: String) -> Self {
         Self {
-            input,
+            source: input,
+            file: 0,
             line: 1,
             col: 0,
             pos: 0,
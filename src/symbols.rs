@@ -0,0 +1,337 @@
+use crate::assemble::size;
+use crate::node::Type;
+use crate::section::Section;
+use crate::source::Sources;
+use crate::stream::Error;
+use crate::token::Range;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+/// Where a symbol points
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    /// An address in an absolute section
+    Absolute(u8),
+    /// An offset into the named relocatable section
+    Relative(String, u8),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Absolute(addr) => write!(f, "0x{:02X}", addr),
+            Self::Relative(sect, offset) => write!(f, "{}+0x{:02X}", sect, offset),
+        }
+    }
+}
+
+/// What defined a symbol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// `name:` in a section
+    Label,
+    /// `name>`, exported to other units
+    Entry,
+    /// A field of a `tplate`, an offset rather than an address
+    Field,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    location: Location,
+    range: Range,
+    kind: Kind,
+}
+
+impl Symbol {
+    #[must_use]
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Where the symbol was defined
+    #[must_use]
+    pub fn range(&self) -> Range {
+        self.range
+    }
+}
+
+/// A named value from `set` or `equ`
+#[derive(Clone, Debug)]
+pub struct Constant {
+    pub value: i32,
+    /// Where it was (last) defined
+    pub range: Range,
+    /// Defined with `set` rather than `equ`, so can be set again
+    pub redefinable: bool,
+}
+
+#[derive(Default, Debug)]
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+    constants: HashMap<String, Constant>,
+    /// Symbols provided by another unit, and where they were declared
+    externals: HashMap<String, Range>,
+}
+
+impl SymbolTable {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// First pass: give every label in `sections` a location
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a label is defined twice, is both defined and
+    /// declared in `externals` or `constants` or an absolute section runs
+    /// past the end of memory
+    pub fn build(
+        sections: &[Rc<RefCell<Section>>],
+        externals: &[(String, Range)],
+        constants: &HashMap<String, Constant>,
+        sources: &Sources,
+    ) -> Result<Self, Error> {
+        let mut labels = Vec::new();
+        for sect in sections {
+            let sect = sect.borrow();
+            let (mut here, sect_name, content) = match &*sect {
+                Section::Absolute { pos, content } => (*pos as usize, None, content),
+                Section::RSect { name, content } => (0, Some(name), content),
+                Section::Template { name, content } => {
+                    // Fields are offsets from the start of the tplate
+                    let mut offset = 0;
+                    for node in content {
+                        if let Type::Label(field) = &**node {
+                            let location =
+                                Location::Absolute(u8::try_from(offset).map_err(|_| {
+                                    Error::new(
+                                        format!("Field {}.{} is past 255 bytes", name, field),
+                                        node.range(),
+                                    )
+                                })?);
+                            labels.push((
+                                format!("{}.{}", name, field),
+                                location,
+                                node.range(),
+                                Kind::Field,
+                            ));
+                        }
+                        offset += size(node);
+                    }
+                    continue;
+                }
+                Section::None => continue,
+            };
+            for node in content {
+                if let Type::Label(name) | Type::Entry(name) = &**node {
+                    let offset = u8::try_from(here).map_err(|_| {
+                        Error::new(
+                            format!("Label {} is past the end of memory", name),
+                            node.range(),
+                        )
+                    })?;
+                    let location = match &sect_name {
+                        Some(sect) => Location::Relative((*sect).clone(), offset),
+                        None => Location::Absolute(offset),
+                    };
+                    let kind = if matches!(**node, Type::Entry(_)) {
+                        Kind::Entry
+                    } else {
+                        Kind::Label
+                    };
+                    labels.push((name.clone(), location, node.range(), kind));
+                }
+                here += size(node);
+            }
+        }
+
+        // Define in the order they were read so redefinitions point at the
+        // later label, even across included files
+        labels.sort_by_cached_key(|(_, _, range, _)| sources.order(*range));
+        let mut table = Self::new();
+        table.constants.clone_from(constants);
+        for (name, ext) in externals {
+            table.declare(name, *ext)?;
+        }
+        let mut exported: HashMap<&str, Range> = HashMap::new();
+        for (name, location, range, kind) in &labels {
+            if *kind == Kind::Entry {
+                if let Some(first) = exported.insert(name, *range) {
+                    return Err(Error::new(format!("{} is already exported", name), *range)
+                        .note(format!("{} was first exported here", name), first));
+                }
+            }
+            table.define(name, location.clone(), *range, *kind)?;
+        }
+        Ok(table)
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` pointing at both definitions if `name` already exists
+    pub fn define(
+        &mut self,
+        name: &str,
+        location: Location,
+        range: Range,
+        kind: Kind,
+    ) -> Result<(), Error> {
+        if let Some(ext) = self.externals.get(name) {
+            Err(
+                Error::new(format!("{} is external but defined here", name), range)
+                    .note(format!("{} was declared ext here", name), *ext),
+            )
+        } else if let Some(constant) = self.constants.get(name) {
+            Err(
+                Error::new(format!("{} is a constant but defined here", name), range)
+                    .note(format!("{} was defined here", name), constant.range),
+            )
+        } else if let Some(existing) = self.symbols.get(name) {
+            Err(
+                Error::new(format!("Label {} is already defined", name), range)
+                    .note(format!("{} was first defined here", name), existing.range),
+            )
+        } else {
+            self.symbols.insert(
+                name.to_string(),
+                Symbol {
+                    location,
+                    range,
+                    kind,
+                },
+            );
+            Ok(())
+        }
+    }
+
+    /// Declare `name` as provided by another unit
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` pointing at both declarations if `name` is already
+    /// external
+    pub fn declare(&mut self, name: &str, range: Range) -> Result<(), Error> {
+        if let Some(existing) = self.externals.get(name) {
+            Err(
+                Error::new(format!("{} is already declared ext", name), range)
+                    .note(format!("{} was first declared here", name), *existing),
+            )
+        } else if let Some(constant) = self.constants.get(name) {
+            Err(Error::new(
+                format!("{} is a constant but declared ext here", name),
+                range,
+            )
+            .note(format!("{} was defined here", name), constant.range))
+        } else {
+            self.externals.insert(name.to_string(), range);
+            Ok(())
+        }
+    }
+
+    /// Where `name` was declared, if it's external
+    #[must_use]
+    pub fn external(&self, name: &str) -> Option<Range> {
+        self.externals.get(name).copied()
+    }
+
+    /// The `set` or `equ` constant called `name`
+    #[must_use]
+    pub fn constant(&self, name: &str) -> Option<&Constant> {
+        self.constants.get(name)
+    }
+
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+
+    /// Second pass: find where `name` used at `at` points
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` pointing at the use if `name` was never defined
+    pub fn resolve(&self, name: &str, at: Range) -> Result<&Location, Error> {
+        self.symbols
+            .get(name)
+            .map(|sym| &sym.location)
+            .ok_or_else(|| Error::new(format!("Undefined symbol {}", name), at))
+    }
+
+    /// Every label sorted by name, `tplate` fields aren't addresses so
+    /// they're left to `fields`
+    #[must_use]
+    pub fn sorted(&self) -> Vec<(&String, &Symbol)> {
+        let mut symbols: Vec<_> = self
+            .symbols
+            .iter()
+            .filter(|(_, symbol)| symbol.kind != Kind::Field)
+            .collect();
+        symbols.sort_by(|a, b| a.0.cmp(b.0));
+        symbols
+    }
+
+    /// Every `tplate` field sorted by name, with its offset
+    #[must_use]
+    pub fn fields(&self) -> Vec<(&String, &Location)> {
+        let mut fields: Vec<_> = self
+            .symbols
+            .iter()
+            .filter(|(_, symbol)| symbol.kind == Kind::Field)
+            .map(|(name, symbol)| (name, &symbol.location))
+            .collect();
+        fields.sort_by(|a, b| a.0.cmp(b.0));
+        fields
+    }
+
+    /// Every `set` and `equ` constant sorted by name
+    #[must_use]
+    pub fn constants(&self) -> Vec<(&String, &Constant)> {
+        let mut constants: Vec<_> = self.constants.iter().collect();
+        constants.sort_by(|a, b| a.0.cmp(b.0));
+        constants
+    }
+
+    /// Every external sorted by name
+    #[must_use]
+    pub fn externals(&self) -> Vec<&String> {
+        let mut externals: Vec<_> = self.externals.keys().collect();
+        externals.sort();
+        externals
+    }
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let how = if self.redefinable { "set" } else { "equ" };
+        write!(f, "{} {}", how, self.value)
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, symbol) in self.sorted() {
+            writeln!(f, "{:16} {}", name, symbol.location)?;
+        }
+        for (name, offset) in self.fields() {
+            writeln!(f, "{:16} field {}", name, offset)?;
+        }
+        for (name, constant) in self.constants() {
+            writeln!(f, "{:16} {}", name, constant)?;
+        }
+        for name in self.externals() {
+            writeln!(f, "{:16} ext", name)?;
+        }
+        Ok(())
+    }
+}
@@ -1,22 +1,44 @@
+use crate::source::FileId;
+
 use std::fmt;
 use std::ops::Add;
 use std::ops::Deref;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Range {
+    file: FileId,
     start: Point,
     end: Point,
 }
 
 impl Range {
+    /// A range in the main file
+    #[must_use]
     pub fn new(start: Point, end: Point) -> Self {
-        Self { start, end }
+        Self {
+            file: 0,
+            start,
+            end,
+        }
     }
 
+    /// The same range in `file`
+    #[must_use]
+    pub fn in_file(self, file: FileId) -> Self {
+        Self { file, ..self }
+    }
+
+    #[must_use]
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    #[must_use]
     pub fn start(&self) -> Point {
         self.start
     }
 
+    #[must_use]
     pub fn end(&self) -> Point {
         self.end
     }
@@ -27,6 +49,7 @@ impl Add for Range {
 
     fn add(self, rhs: Self) -> Self::Output {
         Self {
+            file: self.file,
             start: self.start,
             end: rhs.end,
         }
@@ -61,14 +84,17 @@ impl fmt::Display for Range {
 pub struct Point(usize, usize);
 
 impl Point {
+    #[must_use]
     pub fn new(line: usize, column: usize) -> Self {
         Self(line, column)
     }
 
+    #[must_use]
     pub fn line(&self) -> usize {
         self.0
     }
 
+    #[must_use]
     pub fn column(&self) -> usize {
         self.1
     }
@@ -111,12 +137,24 @@ pub enum Type {
     Hexadecimal(u8),
     /// 0b01010101
     Binary(u8),
-    /// blah
+    /// 0o377
+    Octal(u8),
+    /// "blah"
     Text(String),
+    /// 'c'
+    Character(u8),
     /// # blah
     Comment(String),
     /// An "entry point"
     Entry(String),
+    /// $1, a macro parameter
+    Parameter(u8),
+    /// $name, a label local to one macro expansion
+    Local(String),
+    /// 1b, the nearest `1:` before
+    Backward(u8),
+    /// 1f, the nearest `1:` after
+    Forward(u8),
     /// ,
     Comma,
     /// -
@@ -139,6 +177,10 @@ pub enum Type {
     Exclame,
     /// .
     Dot,
+    /// (
+    LeftParen,
+    /// )
+    RightParen,
     Eof,
 }
 
@@ -150,9 +192,15 @@ impl fmt::Display for Type {
             Self::Decimal(num) => write!(f, "{}", num),
             Self::Hexadecimal(num) => write!(f, "0x{:X}", num),
             Self::Binary(num) => write!(f, "0b{:b}", num),
-            Self::Text(txt) => write!(f, "\"{}\"", txt),
+            Self::Octal(num) => write!(f, "0o{:o}", num),
+            Self::Text(txt) => write!(f, "\"{}\"", txt.escape_default()),
+            Self::Character(byte) => write!(f, "'{}'", char::from(*byte).escape_default()),
             Self::Entry(txt) => write!(f, "_{}", txt),
             Self::Comment(txt) => write!(f, "#{}", txt),
+            Self::Parameter(num) => write!(f, "${}", num),
+            Self::Local(name) => write!(f, "${}", name),
+            Self::Backward(num) => write!(f, "{}b", num),
+            Self::Forward(num) => write!(f, "{}f", num),
             Self::Comma => write!(f, ","),
             Self::Add => write!(f, "+"),
             Self::Minus => write!(f, "-"),
@@ -164,6 +212,8 @@ impl fmt::Display for Type {
             Self::Question => write!(f, "?"),
             Self::Exclame => write!(f, "!"),
             Self::Dot => write!(f, "."),
+            Self::LeftParen => write!(f, "("),
+            Self::RightParen => write!(f, ")"),
             Self::Eof => Ok(()),
         }
     }
@@ -0,0 +1,336 @@
+use crate::assemble::size;
+use crate::link::VECTORS;
+use crate::node::{Node, Type};
+use crate::section::Section;
+use crate::source::{FileId, Sources};
+use crate::stream::Error;
+use crate::token::{Point, Range};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+/// A kind of warning, which `allow` comments can name by code or by name
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Code {
+    /// A value doesn't fit in a byte
+    Wrap,
+    /// A label nothing refers to
+    UnusedLabel,
+    /// Instructions straight after `br` or `halt` with no label to reach them
+    Unreachable,
+    /// `dc` or `ds` that execution runs into
+    DataInCode,
+    /// Something other than vectors placed from 0xF0
+    InterruptVector,
+    /// A negative value where an address or count is expected
+    Signed,
+}
+
+impl Code {
+    pub const ALL: [Self; 6] = [
+        Self::Wrap,
+        Self::UnusedLabel,
+        Self::Unreachable,
+        Self::DataInCode,
+        Self::InterruptVector,
+        Self::Signed,
+    ];
+
+    /// The stable number, such as `W1`
+    #[must_use]
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::Wrap => "W1",
+            Self::UnusedLabel => "W2",
+            Self::Unreachable => "W3",
+            Self::DataInCode => "W4",
+            Self::InterruptVector => "W5",
+            Self::Signed => "W6",
+        }
+    }
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Wrap => "wrap",
+            Self::UnusedLabel => "unused-label",
+            Self::Unreachable => "unreachable",
+            Self::DataInCode => "data-in-code",
+            Self::InterruptVector => "interrupt-vector",
+            Self::Signed => "signed",
+        }
+    }
+
+    /// The code called `text`, by name or number
+    #[must_use]
+    pub fn named(text: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|code| text.eq_ignore_ascii_case(code.id()) || text == code.name())
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.id(), self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct Warning {
+    code: Code,
+    error: Error,
+}
+
+impl Warning {
+    #[must_use]
+    pub fn new(code: Code, message: String, at: Range) -> Self {
+        Self {
+            code,
+            error: Error::new(message + &format!(" [{}]", code), at),
+        }
+    }
+
+    /// A warning that isn't tied to a place in the source
+    #[must_use]
+    pub fn general(code: Code, message: String) -> Self {
+        Self {
+            code,
+            error: Error::general(message + &format!(" [{}]", code)),
+        }
+    }
+
+    #[must_use]
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    #[must_use]
+    pub fn at(&self) -> Option<Range> {
+        self.error.at()
+    }
+
+    pub fn print(&self, src: Option<&Sources>) {
+        self.error.print(src);
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+/// The warnings turned off by comments, `allow` for its own line and the
+/// next and `allow-file` for the whole file (but not what it includes)
+#[derive(Clone, Default)]
+pub struct Allowed {
+    files: HashMap<FileId, HashSet<Code>>,
+    lines: HashMap<(FileId, usize), HashSet<Code>>,
+}
+
+impl Allowed {
+    /// Note the codes turned off by `comment`, the text after the `#` of the
+    /// comment at `at`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it names a code that doesn't exist
+    pub fn comment(&mut self, comment: &str, at: Range) -> Result<(), Error> {
+        let text = comment.trim_start();
+        let (file, rest) = if let Some(rest) = text.strip_prefix("allow-file(") {
+            (true, rest)
+        } else if let Some(rest) = text.strip_prefix("allow(") {
+            (false, rest)
+        } else {
+            return Ok(());
+        };
+        // Byte `pos` of `comment` as a point on its line, past the #
+        let point = |pos: usize| {
+            Point::new(
+                at.start().line(),
+                at.start().column() + 1 + comment[..pos].chars().count(),
+            )
+        };
+        let mut pos = comment.len() - rest.len();
+        let list = rest.split(')').next().unwrap_or("");
+        for item in list.split(',') {
+            let name = item.trim();
+            let start = pos + item.len() - item.trim_start().len();
+            pos += item.len() + 1;
+            let code = Code::named(name).ok_or_else(|| {
+                let range = Range::new(point(start), point(start + name.len()));
+                Error::new(
+                    format!("There's no warning called {}", name),
+                    range.in_file(at.file()),
+                )
+            })?;
+            if file {
+                self.files.entry(at.file()).or_default().insert(code);
+            } else {
+                let line = at.start().line();
+                for line in line..=line + 1 {
+                    self.lines
+                        .entry((at.file(), line))
+                        .or_default()
+                        .insert(code);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the codes turned off in another unit, whose files now start at
+    /// `base`
+    pub fn append(&mut self, other: &Allowed, base: FileId) {
+        for (file, codes) in &other.files {
+            self.files
+                .entry(file + base)
+                .or_default()
+                .extend(codes.iter().copied());
+        }
+        for ((file, line), codes) in &other.lines {
+            self.lines
+                .entry((file + base, *line))
+                .or_default()
+                .extend(codes.iter().copied());
+        }
+    }
+
+    /// Has `warning` been turned off
+    #[must_use]
+    pub fn allows(&self, warning: &Warning) -> bool {
+        let listed = |codes: Option<&HashSet<Code>>| {
+            codes.is_some_and(|codes| codes.contains(&warning.code))
+        };
+        warning.at().is_some_and(|at| {
+            listed(self.files.get(&at.file()))
+                || listed(self.lines.get(&(at.file(), at.start().line())))
+        })
+    }
+}
+
+/// Look over the parsed `sections` for likely mistakes
+pub(crate) fn check(sections: &[Rc<RefCell<Section>>], sources: &Sources) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut used = HashSet::new();
+    for sect in sections {
+        if let Section::Absolute { content, .. } | Section::RSect { content, .. } = &*sect.borrow()
+        {
+            for operand in content.iter().flat_map(|node| node.operands()) {
+                references(operand, &mut used);
+            }
+        }
+    }
+    for sect in sections {
+        let sect = sect.borrow();
+        let (start, content) = match &*sect {
+            Section::Absolute { pos, content } => (Some(*pos as usize), content),
+            Section::RSect { content, .. } => (None, content),
+            Section::Template { .. } | Section::None => continue,
+        };
+        unused(content, &used, &mut warnings);
+        flow(content, start == Some(0), sources, &mut warnings);
+        if let Some(start) = start {
+            vectors(start, content, sources, &mut warnings);
+        }
+    }
+    warnings
+}
+
+/// Every label the expression `node` refers to
+fn references(node: &Node, used: &mut HashSet<String>) {
+    if let Type::Label(name) = &**node {
+        used.insert(name.clone());
+    }
+    for operand in node.operands() {
+        references(operand, used);
+    }
+}
+
+/// Labels defined in `content` that nothing refers to, ignoring the ones
+/// generated for macros and structured code
+fn unused(content: &[Node], used: &HashSet<String>, warnings: &mut Vec<Warning>) {
+    for node in content {
+        if let Type::Label(name) = &**node {
+            if !name.contains('$') && !used.contains(name) {
+                warnings.push(Warning::new(
+                    Code::UnusedLabel,
+                    format!("{} is never used", name),
+                    node.site(),
+                ));
+            }
+        }
+    }
+}
+
+/// How `node` was written, so `1b` isn't shown as the label it stands for
+fn written(node: &Node, sources: &Sources) -> String {
+    let text = if node.generated() {
+        None
+    } else {
+        sources.written(node.range())
+    };
+    text.unwrap_or_else(|| (**node).to_string())
+}
+
+/// Instructions that can't be reached and data that execution runs into,
+/// `entry` if execution starts at the top of `content`. Unreachable code
+/// the assembler made itself is left alone, it can't be changed
+fn flow(content: &[Node], entry: bool, sources: &Sources, warnings: &mut Vec<Warning>) {
+    // Execution can continue into the next node
+    let mut falls = entry;
+    // Nothing jumps to the next node
+    let mut dead = false;
+    for node in content {
+        match &**node {
+            Type::Label(_) | Type::Entry(_) => dead = false,
+            _ if size(node) == 0 => (),
+            Type::Dc(_) | Type::Ds(_) => {
+                if falls {
+                    warnings.push(Warning::new(
+                        Code::DataInCode,
+                        format!("Execution runs into {}", written(node, sources)),
+                        node.site(),
+                    ));
+                }
+                falls = false;
+            }
+            data => {
+                if dead && !node.generated() {
+                    warnings.push(Warning::new(
+                        Code::Unreachable,
+                        format!("{} can't be reached", written(node, sources)),
+                        node.site(),
+                    ));
+                }
+                falls = !matches!(data, Type::Br(_) | Type::Halt | Type::Rts | Type::Rti);
+                dead = matches!(data, Type::Br(_) | Type::Halt);
+            }
+        }
+    }
+}
+
+/// Anything but a table of vectors in the interrupt vector area
+fn vectors(start: usize, content: &[Node], sources: &Sources, warnings: &mut Vec<Warning>) {
+    let mut addr = start;
+    for node in content {
+        let end = addr + size(node);
+        let table = start >= VECTORS && matches!(**node, Type::Dc(_));
+        if end > VECTORS && addr < end && !table {
+            warnings.push(Warning::new(
+                Code::InterruptVector,
+                format!(
+                    "{} is placed in the interrupt vectors (0xF0-0xFF)",
+                    written(node, sources)
+                ),
+                node.site(),
+            ));
+            return;
+        }
+        addr = end;
+    }
+}